@@ -0,0 +1,248 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::rand::{Rng, task_rng};
+
+use super::events;
+use super::events::EventSource;
+use super::IoResult;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::timer::{Timer, VirtualClock};
+
+/// Determines how the delay grows between two attempts
+pub enum BackoffPolicy {
+	/// Always waits the given number of milliseconds
+	ConstantBackoff(u32),
+	/// Starts with the given number of milliseconds and doubles the delay after each attempt
+	ExponentialBackoff(u32),
+	/// Picks a random delay between the given base and three times the previous delay
+	DecorrelatedJitterBackoff(u32)
+}
+
+/**
+ * A singleshot timer for retry loops.
+ * Each call to `next()` arms the timer with the next delay of the policy.
+ * When the maximum number of attempts is reached `next()` queues a single
+ * BackoffGiveUpEvent instead. All events originate from the BackoffTimer.
+ */
+pub struct BackoffTimer {
+	priv timer: ~Timer,
+	priv policy: BackoffPolicy,
+	priv max_interval: u32,
+	priv max_attempts: uint,
+	priv attempts: uint,
+	priv gave_up: bool,
+	priv current_interval: u32,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>
+}
+
+impl BackoffTimer {
+	pub fn create(event_queue: &EventQueue, policy: BackoffPolicy) -> IoResult<~BackoffTimer> {
+		Timer::create(event_queue).map(|timer|
+			BackoffTimer::from_timer(timer, event_queue, policy))
+	}
+
+	pub fn create_with_clock(event_queue: &EventQueue, clock: &VirtualClock,
+	                         policy: BackoffPolicy) -> IoResult<~BackoffTimer> {
+		Timer::create_with_clock(event_queue, clock).map(|timer|
+			BackoffTimer::from_timer(timer, event_queue, policy))
+	}
+
+	fn from_timer(mut timer: ~Timer, event_queue: &EventQueue, policy: BackoffPolicy) -> ~BackoffTimer {
+		timer.set_singleshot(true);
+		~BackoffTimer {
+			timer: timer,
+			policy: policy,
+			max_interval: 0,
+			max_attempts: 0,
+			attempts: 0,
+			gave_up: false,
+			current_interval: 0,
+			event_queue: event_queue._get_impl()
+		}
+	}
+
+	/// Limits the delay between two attempts. 0 means no limit.
+	pub fn set_max_interval(&mut self, max_interval: u32) {
+		self.max_interval = max_interval;
+	}
+
+	pub fn get_max_interval(&self) -> u32 {
+		self.max_interval
+	}
+
+	/// Sets the number of attempts after which `next()` gives up. 0 means no limit.
+	pub fn set_max_attempts(&mut self, max_attempts: uint) {
+		self.max_attempts = max_attempts;
+	}
+
+	pub fn get_max_attempts(&self) -> uint {
+		self.max_attempts
+	}
+
+	/// Returns the number of attempts since the last reset
+	pub fn attempts(&self) -> uint {
+		self.attempts
+	}
+
+	/// Returns the delay that was used for the last attempt
+	pub fn current_interval(&self) -> u32 {
+		self.current_interval
+	}
+
+	pub fn is_active(&self) -> bool {
+		self.timer.is_active()
+	}
+
+	/**
+	 * Must be called after a failed attempt.
+	 * Arms the timer and returns the next delay. Returns None if no attempts
+	 * are left. The first of these calls queues a BackoffGiveUpEvent,
+	 * further calls do nothing until the timer is reset.
+	 */
+	pub fn next(&mut self) -> Option<u32> {
+		if self.gave_up {
+			return None;
+		}
+		self.timer.stop();
+
+		if self.max_attempts != 0 && self.attempts >= self.max_attempts {
+			self.gave_up = true;
+			let e = events::Event {
+				event_type: events::BackoffGiveUpEvent,
+				is_valid: true,
+				source_info: self.timer.get_event_source_info().clone()
+			};
+			self.event_queue.borrow().with_mut(|q| q.push_back_event(e));
+			return None;
+		}
+
+		self.current_interval = self.next_interval();
+		self.attempts += 1;
+		self.timer.set_interval(self.current_interval);
+		self.timer.start();
+		Some(self.current_interval)
+	}
+
+	/// Must be called after a successful attempt. Stops the timer and starts over.
+	pub fn reset(&mut self) {
+		self.timer.stop();
+		self.attempts = 0;
+		self.gave_up = false;
+		self.current_interval = 0;
+	}
+
+	/// Returns true if `next()` gave up since the last reset
+	pub fn has_given_up(&self) -> bool {
+		self.gave_up
+	}
+
+	fn next_interval(&self) -> u32 {
+		let interval: u64 = match self.policy {
+			ConstantBackoff(interval) => interval as u64,
+			ExponentialBackoff(initial) => {
+				let shift = cmp::min(self.attempts, 32);
+				(initial as u64) << shift
+			},
+			DecorrelatedJitterBackoff(base) => {
+				let upper = cmp::max(self.current_interval as u64 * 3, base as u64);
+				if upper > base as u64 {
+					task_rng().gen_range(base as u64, upper + 1)
+				}
+				else {
+					base as u64
+				}
+			}
+		};
+
+		let mut interval = cmp::min(interval, 0xffffffffu64) as u32;
+		if self.max_interval != 0 && interval > self.max_interval {
+			interval = self.max_interval;
+		}
+		// A timer with interval 0 won't start
+		cmp::max(interval, 1)
+	}
+}
+
+impl events::EventSource for BackoffTimer {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		self.timer.get_event_source_info()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use timer::VirtualClock;
+	use super::{BackoffTimer, ConstantBackoff, ExponentialBackoff};
+
+	fn pending(queue: &EventQueue) -> uint {
+		queue._get_impl().borrow().with(|q| q.pending_events())
+	}
+
+	#[test]
+	fn exponential_delays_are_capped() {
+		let queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut backoff = BackoffTimer::create_with_clock(&queue, &clock, ExponentialBackoff(100)).unwrap();
+		backoff.set_max_interval(500);
+		assert_eq!(backoff.next(), Some(100));
+		assert_eq!(backoff.next(), Some(200));
+		assert_eq!(backoff.next(), Some(400));
+		assert_eq!(backoff.next(), Some(500));
+		assert_eq!(backoff.attempts(), 4);
+		backoff.reset();
+		assert_eq!(backoff.next(), Some(100));
+	}
+
+	#[test]
+	fn timer_fires_after_delay() {
+		let mut queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut backoff = BackoffTimer::create_with_clock(&queue, &clock, ConstantBackoff(50)).unwrap();
+		backoff.next();
+		clock.advance(49);
+		assert_eq!(pending(&queue), 0);
+		clock.advance(1);
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::TimerEvent => {},
+			_ => fail!("Expected a TimerEvent")
+		}
+		assert!(event.originates_from(backoff));
+		assert!(!backoff.is_active());
+	}
+
+	#[test]
+	fn gives_up_once() {
+		let mut queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut backoff = BackoffTimer::create_with_clock(&queue, &clock, ConstantBackoff(10)).unwrap();
+		backoff.set_max_attempts(2);
+		assert!(backoff.next().is_some());
+		assert!(backoff.next().is_some());
+		assert_eq!(backoff.next(), None);
+		assert_eq!(backoff.next(), None);
+		assert!(backoff.has_given_up());
+		clock.advance(100); // The last attempt is stopped by the give up
+		assert_eq!(pending(&queue), 1);
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::BackoffGiveUpEvent => {},
+			_ => fail!("Expected a BackoffGiveUpEvent")
+		}
+	}
+}
@@ -0,0 +1,159 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/*!
+ * Adapters that deliver messages from foreign sources as ChannelMessageEvents.
+ *
+ * Blocking sources like a std `Port` are drained by a native thread which
+ * forwards each message through a revbio channel. APIs that push messages
+ * from another thread through a callback don't need an adapter: the
+ * callback can capture a clone of the `Transmitter` that `Channel::create`
+ * returns.
+ *
+ * The forwarding thread can't be cancelled. It blocks in the `recv_opt`
+ * of the source and only notices that the Receiver was dropped when it
+ * tries to forward the next message. A source that never delivers or
+ * closes keeps its thread alive until the process exits. Close the
+ * source, e.g. by dropping the `Chan` of a forwarded `Port`, to stop it.
+ */
+
+use native;
+
+use super::eventqueue::EventQueue;
+use super::channel::{Channel, Receiver};
+
+/// A source of messages whose `recv` blocks until the next message is available
+pub trait BlockingSource<T> {
+	/// Returns None when the source is closed
+	fn recv_opt(&mut self) -> Option<T>;
+}
+
+impl<T:Send> BlockingSource<T> for Port<T> {
+	fn recv_opt(&mut self) -> Option<T> {
+		let port: &Port<T> = self;
+		port.recv_opt()
+	}
+}
+
+/**
+ * Spawns a native thread that forwards all messages of the source to the
+ * returned Receiver. The Receiver queues a ChannelClosedEvent after the
+ * source is closed and all messages were forwarded.
+ * If the Receiver is dropped first, the thread stops after the next message
+ * or when the source is closed. See the module docs on cancellation.
+ */
+pub fn forward<T:Send, S:BlockingSource<T>+Send>(source: S, event_queue: &EventQueue) -> ~Receiver<T> {
+	let (rx, tx) = Channel::create(event_queue);
+	native::task::spawn(proc() {
+		let mut source = source;
+		loop {
+			match source.recv_opt() {
+				Some(t) => {
+					if tx.send(t).is_err() { break; }
+				},
+				None => break
+			}
+		}
+	});
+	rx
+}
+
+/// Forwards the messages of a std Port as described in `forward`
+pub fn from_port<T:Send>(port: Port<T>, event_queue: &EventQueue) -> ~Receiver<T> {
+	forward(port, event_queue)
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use super::{BlockingSource, forward, from_port};
+
+	fn pending(queue: &EventQueue) -> uint {
+		queue._get_impl().borrow().with(|q| q.pending_events())
+	}
+
+	// Reports through `dropped` when the forwarding thread has released it
+	struct WatchedPort {
+		port: Port<int>,
+		dropped: Chan<()>
+	}
+
+	impl BlockingSource<int> for WatchedPort {
+		fn recv_opt(&mut self) -> Option<int> {
+			self.port.recv_opt()
+		}
+	}
+
+	impl Drop for WatchedPort {
+		fn drop(&mut self) {
+			self.dropped.send(());
+		}
+	}
+
+	#[test]
+	fn port_messages_arrive_before_the_closed_event() {
+		let mut queue = EventQueue::new();
+		let (port, chan) = Chan::<int>::new();
+		let mut rx = from_port(port, &queue);
+		for i in range(0, 5) {
+			chan.send(i);
+		}
+		drop(chan);
+
+		let mut received = ~[];
+		loop {
+			let event = queue.next_event().unwrap();
+			assert!(event.originates_from(rx));
+			match event.event_type {
+				events::ChannelMessageEvent => {
+					match rx.recv() {
+						Some(i) => received.push(i),
+						None => {}
+					}
+				},
+				events::ChannelClosedEvent => break,
+				_ => fail!("Unexpected event")
+			}
+		}
+		assert_eq!(received, ~[0, 1, 2, 3, 4]);
+		assert!(rx.recv().is_none());
+	}
+
+	#[test]
+	fn bridge_stops_when_the_receiver_is_dropped() {
+		let queue = EventQueue::new();
+		let (port, chan) = Chan::<int>::new();
+		let (dropped_port, dropped_chan) = Chan::<()>::new();
+		let rx = forward(WatchedPort{port: port, dropped: dropped_chan}, &queue);
+		drop(rx);
+		// The thread only notices the dropped Receiver on the next message
+		chan.send(1);
+		dropped_port.recv();
+		assert!(!chan.try_send(2));
+	}
+
+	#[test]
+	fn bridge_stops_when_the_source_is_closed() {
+		let mut queue = EventQueue::new();
+		let (port, chan) = Chan::<int>::new();
+		let (dropped_port, dropped_chan) = Chan::<()>::new();
+		let rx = forward(WatchedPort{port: port, dropped: dropped_chan}, &queue);
+		drop(chan);
+		dropped_port.recv();
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::ChannelClosedEvent => {},
+			_ => fail!("Expected a ChannelClosedEvent")
+		}
+		assert!(event.originates_from(rx));
+		assert_eq!(pending(&queue), 0);
+	}
+}
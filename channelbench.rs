@@ -69,6 +69,48 @@ fn bench_conditions_main() {
 	let diff = timediff(start_time, end_time);
 	println!("Revio blocking channels: Diff: {:?}", diff);
 
+	let producers = 4u32;
+
+	let (port,chan): (Port<i32>, Chan<i32>) = Chan::new();
+	let start_time = time::get_time();
+
+	for _ in range(0,producers) {
+		let chan = chan.clone();
+		native::task::spawn(proc() {
+			for _ in range(0,ITERATIONS/producers) {
+				chan.send(0);
+			}
+		});
+	}
+
+	for _ in range(0,ITERATIONS/producers*producers) {
+		port.recv();
+	}
+
+	let end_time = time::get_time();
+	let diff = timediff(start_time, end_time);
+	println!("Native channels with {} producers: Diff: {:?}", producers, diff);
+
+	let (rx,tx): (BlockingReceiver<i32>, Transmitter<i32>) = Channel::create_blocking();
+	let start_time = time::get_time();
+
+	for _ in range(0,producers) {
+		let tx = tx.clone();
+		native::task::spawn(proc() {
+			for _ in range(0,ITERATIONS/producers) {
+				tx.send(0);
+			}
+		});
+	}
+
+	for _ in range(0,ITERATIONS/producers*producers) {
+		rx.recv();
+	}
+
+	let end_time = time::get_time();
+	let diff = timediff(start_time, end_time);
+	println!("Revio blocking channels with {} producers: Diff: {:?}", producers, diff);
+
 	let mut ev_queue = EventQueue::new();
 	let (mut rx,tx): (~Receiver<i32>, Transmitter<i32>) = Channel::create(&ev_queue);
 	let mut nr_received = 0u32;
@@ -0,0 +1,246 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io;
+use std::io::IoError;
+use std::ascii::StrAsciiExt;
+
+use super::IoResult;
+
+static MONTH_NAMES: [&'static str, ..12] = [
+	"JAN", "FEB", "MAR", "APR", "MAY", "JUN",
+	"JUL", "AUG", "SEP", "OCT", "NOV", "DEC"
+];
+
+static DAY_NAMES: [&'static str, ..7] = [
+	"SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"
+];
+
+/**
+ * A parsed cron expression with the 5 standard fields
+ * `minute hour day-of-month month day-of-week`.
+ * Fields support `*`, lists, ranges, steps and month and day names.
+ * The macros @yearly, @annually, @monthly, @weekly, @daily, @midnight
+ * and @hourly are supported too.
+ */
+pub struct CronSchedule {
+	priv minutes: u64,
+	priv hours: u64,
+	priv days_of_month: u64,
+	priv months: u64,
+	priv days_of_week: u64,
+	priv dom_restricted: bool,
+	priv dow_restricted: bool
+}
+
+fn invalid_expression(detail: ~str) -> IoError {
+	IoError {
+		kind: io::InvalidInput,
+		desc: "Invalid cron expression",
+		detail: Some(detail)
+	}
+}
+
+fn parse_value(value: &str, min: uint, max: uint, names: Option<&[&'static str]>) -> IoResult<uint> {
+	match names {
+		Some(names) => {
+			for (i, name) in names.iter().enumerate() {
+				if value.eq_ignore_ascii_case(*name) {
+					return Ok(i + min);
+				}
+			}
+		},
+		None => {}
+	}
+	match from_str::<uint>(value) {
+		Some(v) if v >= min && v <= max => Ok(v),
+		_ => Err(invalid_expression(format!("{} is not in range {}-{}", value, min, max)))
+	}
+}
+
+/// Parses a single field into a bitmask. Returns the mask and whether the field was `*`.
+fn parse_field(field: &str, min: uint, max: uint, names: Option<&[&'static str]>) -> IoResult<(u64, bool)> {
+	let mut mask = 0u64;
+	let unrestricted = field.starts_with("*");
+
+	for part in field.split(',') {
+		let (range, step) = match part.find('/') {
+			Some(pos) => {
+				let step = part.slice_from(pos + 1);
+				match from_str::<uint>(step) {
+					Some(s) if s > 0 => (part.slice_to(pos), s),
+					_ => return Err(invalid_expression(format!("Invalid step {}", step)))
+				}
+			},
+			None => (part, 1)
+		};
+
+		let (first, last) = if range == "*" {
+			(min, max)
+		}
+		else {
+			match range.find('-') {
+				Some(pos) => {
+					let first = if_ok!(parse_value(range.slice_to(pos), min, max, names));
+					let last = if_ok!(parse_value(range.slice_from(pos + 1), min, max, names));
+					if first > last {
+						return Err(invalid_expression(format!("Invalid range {}", range)));
+					}
+					(first, last)
+				},
+				None => {
+					let first = if_ok!(parse_value(range, min, max, names));
+					// a/n means every n-th value starting from a
+					if step > 1 { (first, max) } else { (first, first) }
+				}
+			}
+		};
+
+		let mut v = first;
+		while v <= last {
+			mask |= 1 << v;
+			v += step;
+		}
+	}
+
+	Ok((mask, unrestricted))
+}
+
+impl CronSchedule {
+	pub fn parse(expr: &str) -> IoResult<CronSchedule> {
+		let expr = match expr.trim() {
+			"@yearly" | "@annually" => "0 0 1 1 *",
+			"@monthly" => "0 0 1 * *",
+			"@weekly" => "0 0 * * 0",
+			"@daily" | "@midnight" => "0 0 * * *",
+			"@hourly" => "0 * * * *",
+			other => other
+		};
+
+		let fields: ~[&str] = expr.words().collect();
+		if fields.len() != 5 {
+			return Err(invalid_expression(format!("Expected 5 fields but got {}", fields.len())));
+		}
+
+		let (minutes, _) = if_ok!(parse_field(fields[0], 0, 59, None));
+		let (hours, _) = if_ok!(parse_field(fields[1], 0, 23, None));
+		let (days_of_month, dom_all) = if_ok!(parse_field(fields[2], 1, 31, None));
+		let (months, _) = if_ok!(parse_field(fields[3], 1, 12, Some(MONTH_NAMES.as_slice())));
+		let (mut days_of_week, dow_all) = if_ok!(parse_field(fields[4], 0, 7, Some(DAY_NAMES.as_slice())));
+		// 7 is an alias for sunday
+		if days_of_week & (1 << 7) != 0 {
+			days_of_week = (days_of_week & !(1 << 7)) | 1;
+		}
+
+		Ok(CronSchedule {
+			minutes: minutes,
+			hours: hours,
+			days_of_month: days_of_month,
+			months: months,
+			days_of_week: days_of_week,
+			dom_restricted: !dom_all,
+			dow_restricted: !dow_all
+		})
+	}
+
+	pub fn matches_minute(&self, minute: uint) -> bool {
+		self.minutes & (1 << minute) != 0
+	}
+
+	pub fn matches_hour(&self, hour: uint) -> bool {
+		self.hours & (1 << hour) != 0
+	}
+
+	/// Month is in the range 1-12
+	pub fn matches_month(&self, month: uint) -> bool {
+		self.months & (1 << month) != 0
+	}
+
+	/**
+	 * Day of month is in the range 1-31, day of week in 0-6 with 0 being sunday.
+	 * Like in cron a day matches if either field matches when both are restricted.
+	 */
+	pub fn matches_day(&self, day_of_month: uint, day_of_week: uint) -> bool {
+		let dom = self.days_of_month & (1 << day_of_month) != 0;
+		let dow = self.days_of_week & (1 << day_of_week) != 0;
+		if self.dom_restricted && self.dow_restricted {
+			dom || dow
+		}
+		else {
+			dom && dow
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::CronSchedule;
+
+	#[test]
+	fn parses_lists_ranges_and_steps() {
+		let schedule = CronSchedule::parse("0,30 9-17/4 * * *").unwrap();
+		assert!(schedule.matches_minute(0));
+		assert!(schedule.matches_minute(30));
+		assert!(!schedule.matches_minute(15));
+		assert!(schedule.matches_hour(9));
+		assert!(schedule.matches_hour(13));
+		assert!(schedule.matches_hour(17));
+		assert!(!schedule.matches_hour(10));
+		assert!(!schedule.matches_hour(21));
+	}
+
+	#[test]
+	fn start_with_step_runs_to_the_end() {
+		let schedule = CronSchedule::parse("5/20 * * * *").unwrap();
+		assert!(schedule.matches_minute(5));
+		assert!(schedule.matches_minute(25));
+		assert!(schedule.matches_minute(45));
+		assert!(!schedule.matches_minute(0));
+	}
+
+	#[test]
+	fn parses_names_and_sunday_alias() {
+		let schedule = CronSchedule::parse("0 0 * jan-mar SUN").unwrap();
+		assert!(schedule.matches_month(1));
+		assert!(schedule.matches_month(3));
+		assert!(!schedule.matches_month(4));
+		let sunday = CronSchedule::parse("0 0 * * 7").unwrap();
+		assert!(sunday.matches_day(10, 0));
+		assert!(!sunday.matches_day(10, 6));
+	}
+
+	#[test]
+	fn day_fields_are_ored_when_both_are_restricted() {
+		let schedule = CronSchedule::parse("0 0 1 * MON").unwrap();
+		assert!(schedule.matches_day(1, 3));
+		assert!(schedule.matches_day(15, 1));
+		assert!(!schedule.matches_day(15, 2));
+		let dom_only = CronSchedule::parse("0 0 1 * *").unwrap();
+		assert!(dom_only.matches_day(1, 3));
+		assert!(!dom_only.matches_day(15, 1));
+	}
+
+	#[test]
+	fn expands_macros() {
+		let schedule = CronSchedule::parse("@hourly").unwrap();
+		assert!(schedule.matches_minute(0));
+		assert!(!schedule.matches_minute(1));
+		assert!(schedule.matches_hour(13));
+	}
+
+	#[test]
+	fn rejects_invalid_expressions() {
+		assert!(CronSchedule::parse("* * * *").is_err());
+		assert!(CronSchedule::parse("60 * * * *").is_err());
+		assert!(CronSchedule::parse("* 5-2 * * *").is_err());
+		assert!(CronSchedule::parse("*/0 * * * *").is_err());
+		assert!(CronSchedule::parse("* * 0 * *").is_err());
+	}
+}
@@ -9,6 +9,7 @@
 // except according to those terms.
 
 use std::io::IoError;
+use std::io::process::ProcessExit;
 use std::rc::{Rc, Weak};
 
 pub enum EventKind
@@ -16,13 +17,45 @@ pub enum EventKind
 	StreamClosedEvent,
 	IoErrorEvent(IoError),
 	DataAvailableEvent(uint),
+	/// All buffered data of a writer was written
+	WriteCompletedEvent,
 	TimerEvent,
+	BackoffGiveUpEvent,
 	ChannelClosedEvent,
 	ChannelMessageEvent,
+	ChannelMessagesEvent(uint),
+	ChannelWritableEvent,
+	ChannelReceiverClosedEvent,
+	ChannelLaggedEvent(uint),
+	ChannelPriorityMessageEvent(uint),
+	ChannelHighWaterEvent(uint),
+	ChannelLowWaterEvent(uint),
+	ValueChangedEvent,
+	OneshotCompletedEvent,
+	OneshotCanceledEvent,
+	/// The signal number and details about the signal
+	SignalEvent(int, SignalInfo),
+	ProcessExitedEvent(ProcessExit),
+	ProcessTimedOutEvent,
 	ConnectedEvent,
 	ClientConnectedEvent
 }
 
+/// Details about a received signal
+#[deriving(Clone)]
+pub struct SignalInfo {
+	/// The signal number
+	signo: int,
+	/// Why the signal was sent, e.g. SI_USER or CLD_EXITED
+	code: int,
+	/// The process that sent the signal or the child that changed its state
+	pid: u32,
+	/// The real user ID of the sending process
+	uid: u32,
+	/// Exit status or signal of a child for SIGCHLD
+	status: int
+}
+
 pub struct Event
 {
 	event_type: EventKind,
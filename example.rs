@@ -73,9 +73,11 @@ fn subtask(tx: Transmitter<~str>) {
 
 	loop {
 		let event = ev_queue.next_event().unwrap();
+		let mut messages: ~[~str] = ~[];
+		let mut finished = false;
 		
 		if event.originates_from(sub_timer) {
-			tx.send(~"subtimer::tick()");
+			messages.push(~"subtimer::tick()");
 			if !stream_alive {
 				if iterations > 0 {
 					socket = TcpSocket::connect(socketaddr, &ev_queue).unwrap();
@@ -84,26 +86,26 @@ fn subtask(tx: Transmitter<~str>) {
 				else {
 					sub_timer.stop();
 					socket.close();
-					return;	
+					finished = true;
 				}
 			}
 		}
 		else if event.originates_from(socket) {
 			match event.event_type {
 				events::ConnectedEvent => {
-					tx.send(~"TCP stream got connected");
+					messages.push(~"TCP stream got connected");
 					stream_alive = true;
 					let _ = socket.write(request.as_bytes());
 					iterations -= 1;
 				},
 				events::IoErrorEvent(err) => {
-					tx.send(~"IoError");
-					tx.send(err.desc.to_owned());
+					messages.push(~"IoError");
+					messages.push(err.desc.to_owned());
 					stream_alive = false;
 					iterations -= 1;
 				},
 				events::StreamClosedEvent => {
-					tx.send(~"TCP connection closed");
+					messages.push(~"TCP connection closed");
 					stream_alive = false;
 					iterations -= 1;
 				},
@@ -111,13 +113,11 @@ fn subtask(tx: Transmitter<~str>) {
 					let mut buffer: ~[u8] = std::vec::from_elem::<u8>(nr_bytes, 0);
 					let read_res = socket.read(buffer);
 					match read_res {
-						Err(err) => {
-							tx.send(err.desc.to_owned());
-						}
+						Err(err) => messages.push(err.desc.to_owned()),
 						Ok(nr_read) => {
 							let txt = std::str::from_utf8(buffer.slice(0, nr_read));
 							if txt.is_some() {
-								tx.send(txt.unwrap().to_owned());
+								messages.push(txt.unwrap().to_owned());
 							}
 						}
 					}
@@ -125,5 +125,14 @@ fn subtask(tx: Transmitter<~str>) {
 				_ => ()
 			}
 		}
+
+		for msg in messages.move_iter() {
+			if tx.send(msg).is_err() {
+				return; // The main task is gone
+			}
+		}
+		if finished {
+			return;
+		}
 	}	
 }
\ No newline at end of file
@@ -35,6 +35,11 @@ mod eventqueueimpl;
 #[path="linux/timer.rs"]
 pub mod timer;
 
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/schedule.rs"]
+pub mod schedule;
+
 #[cfg(target_os = "linux")]
 #[cfg(target_os = "android")]
 #[path="linux/syscalls.rs"]
@@ -56,5 +61,71 @@ pub mod tcp;
 #[path="linux/channel.rs"]
 pub mod channel;
 
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/workqueue.rs"]
+pub mod workqueue;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/broadcast.rs"]
+pub mod broadcast;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/watch.rs"]
+pub mod watch;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/oneshot.rs"]
+pub mod oneshot;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/ipcchannel.rs"]
+pub mod ipcchannel;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/prioritychannel.rs"]
+pub mod prioritychannel;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/shmring.rs"]
+pub mod shmring;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/signal.rs"]
+pub mod signal;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/pipe.rs"]
+pub mod pipe;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/process.rs"]
+pub mod process;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+pub mod backoff;
+
+pub mod cron;
+mod tzfile;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+#[path="linux/localchannel.rs"]
+pub mod localchannel;
+
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+pub mod bridge;
+
 /// Holds either the success value of an IO operation or an error
 pub type IoResult<T> = Result<T, IoError>;
\ No newline at end of file
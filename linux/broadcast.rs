@@ -0,0 +1,469 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::cmp;
+use std::libc;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::unstable::mutex::Mutex;
+use std::sync::arc::UnsafeArc;
+use collections::ringbuf::RingBuf;
+use collections::deque::Deque;
+
+use super::events;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::syscalls;
+use super::helpers;
+
+pub struct Publisher<T> {
+	priv data: UnsafeArc<SharedBroadcastData<T>>
+}
+
+pub struct BlockingSubscriber<T> {
+	priv data: UnsafeArc<SubscriberData<T>>
+}
+
+struct SharedBroadcastData<T> {
+	mutex: Mutex,
+	nr_publishers: uint,
+	capacity: uint,
+	subscribers: ~[UnsafeArc<SubscriberData<T>>]
+}
+
+/// The buffer of a single subscriber
+struct SubscriberData<T> {
+	queue: RingBuf<T>,
+	mutex: Mutex,
+	alive: bool,
+	closed: bool,
+	// Number of messages that were dropped since the last report
+	lagged: uint,
+	receiver_notified: bool,
+	epoll_fd: i32
+}
+
+/**
+ * A channel that delivers a copy of each message to every subscriber.
+ * Each subscriber buffers at most `capacity` messages. If a subscriber
+ * falls behind the oldest messages are dropped and the subscriber is
+ * informed through a ChannelLaggedEvent.
+ */
+pub struct Broadcast<T>;
+
+impl <T:Send+Clone> Broadcast<T> {
+	pub fn create(capacity: uint) -> Publisher<T> {
+		if capacity == 0 {
+			fail!("Broadcast capacity must be greater than 0");
+		}
+		Publisher {
+			data: UnsafeArc::new(SharedBroadcastData {
+				mutex: unsafe { Mutex::new() },
+				nr_publishers: 1,
+				capacity: capacity,
+				subscribers: ~[]
+			})
+		}
+	}
+}
+
+/// Wakes the receiver of a subscriber. Must be called with the subscriber mutex held.
+unsafe fn notify_subscriber<T>(sub: *mut SubscriberData<T>) {
+	if (*sub).receiver_notified { return; }
+	if (*sub).epoll_fd == -1 {
+		(*sub).mutex.signal();
+	}
+	else if helpers::signal_eventfd((*sub).epoll_fd) == -1 {
+		(*sub).mutex.unlock();
+		fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
+	}
+	(*sub).receiver_notified = true;
+}
+
+impl<T:Send+Clone> Publisher<T> {
+	/**
+	 * Adds a new subscriber. It receives all messages that are sent
+	 * after this call.
+	 */
+	pub fn subscribe(&self) -> BlockingSubscriber<T> {
+		let sub = UnsafeArc::new(SubscriberData {
+			queue: RingBuf::new(),
+			mutex: unsafe { Mutex::new() },
+			alive: true,
+			closed: false,
+			lagged: 0,
+			receiver_notified: false,
+			epoll_fd: -1
+		});
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).subscribers.push(sub.clone());
+			(*data).mutex.unlock();
+		}
+		BlockingSubscriber{data: sub}
+	}
+
+	/// Sends a copy of the message to all subscribers and returns their number
+	pub fn send(&self, t: T) -> uint {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).subscribers.retain(|sub| {
+				let sub = sub.get();
+				(*sub).mutex.lock();
+				let alive = (*sub).alive;
+				(*sub).mutex.unlock();
+				alive
+			});
+			let capacity = (*data).capacity;
+			for sub in (*data).subscribers.iter() {
+				let sub = sub.get();
+				(*sub).mutex.lock();
+				if (*sub).queue.len() >= capacity {
+					(*sub).queue.pop_front();
+					(*sub).lagged += 1;
+				}
+				(*sub).queue.push_back(t.clone());
+				notify_subscriber(sub);
+				(*sub).mutex.unlock();
+			}
+			let count = (*data).subscribers.len();
+			(*data).mutex.unlock();
+			count
+		}
+	}
+
+	pub fn subscriber_count(&self) -> uint {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let count = (*data).subscribers.len();
+			(*data).mutex.unlock();
+			count
+		}
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for Publisher<T> {
+	fn drop(&mut self) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_publishers -= 1;
+			if (*data).nr_publishers == 0 {
+				for sub in (*data).subscribers.iter() {
+					let sub = sub.get();
+					(*sub).mutex.lock();
+					(*sub).closed = true;
+					notify_subscriber(sub);
+					(*sub).mutex.unlock();
+				}
+				(*data).subscribers.clear();
+			}
+			(*data).mutex.unlock();
+		}
+	}
+}
+
+impl<T:Send> Clone for Publisher<T> {
+	fn clone(&self) -> Publisher<T> {
+		let new = Publisher{data: self.data.clone()};
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_publishers += 1;
+			(*data).mutex.unlock();
+		}
+		new
+	}
+}
+
+impl<T:Send> BlockingSubscriber<T> {
+	/// Returns None when all publishers are gone and all messages were received
+	pub fn recv(&self) -> Option<T> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			while (*data).queue.len() < 1 && !(*data).closed {
+				(*data).mutex.wait();
+			}
+			let ret = (*data).queue.pop_front();
+			(*data).receiver_notified = false;
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+
+	pub fn try_recv(&self) -> Option<T> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let ret = (*data).queue.pop_front();
+			(*data).receiver_notified = false;
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+
+	/// Returns the number of messages that were dropped since the last call
+	pub fn take_lagged(&self) -> uint {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let lagged = (*data).lagged;
+			(*data).lagged = 0;
+			(*data).mutex.unlock();
+			lagged
+		}
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for BlockingSubscriber<T> {
+	fn drop(&mut self) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).alive = false;
+			(*data).queue.clear();
+			(*data).mutex.unlock();
+		}
+	}
+}
+
+/**
+ * An evented subscriber.
+ * Queues a ChannelMessageEvent for each message, a ChannelLaggedEvent when
+ * messages had to be dropped and a ChannelClosedEvent when all publishers are gone.
+ */
+pub struct Subscriber<T> {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv subscriber: BlockingSubscriber<T>,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>,
+	priv available_messages: uint,
+	priv closed_reported: bool
+}
+
+impl<T> events::EventSource for Subscriber<T> {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl<T:Send> Subscriber<T> {
+	pub fn from_blocking_subscriber(blocking_subscriber: BlockingSubscriber<T>, event_queue: &EventQueue) -> ~Subscriber<T> {
+		let subscriber = ~Subscriber{
+			subscriber: blocking_subscriber,
+			event_queue: event_queue._get_impl(),
+			process_func: Subscriber::<T>::process_epoll_events,
+			event_source_info: Rc::new(events::EventSourceInfo::new()),
+			available_messages: 0,
+			closed_reported: false
+		};
+
+		let fd = unsafe { syscalls::eventfd(0, 0) };
+		if fd == -1 {
+			fail!("Creating eventfd for subscriber failed: {}", helpers::last_error().desc);
+		}
+		let callback: *libc::c_void = unsafe { cast::transmute(&subscriber.process_func) };
+		subscriber.event_queue.borrow().with_mut(|q|
+			q.register_fd(fd, syscalls::EPOLLIN, callback)
+		);
+
+		let data = subscriber.subscriber.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).epoll_fd = fd;
+			(*data).receiver_notified = false;
+			if (*data).queue.len() > 0 || (*data).closed {
+				notify_subscriber(data);
+			}
+			(*data).mutex.unlock();
+		}
+		subscriber
+	}
+
+	pub fn recv(&mut self) -> Option<T> {
+		if self.available_messages > 0 {
+			self.available_messages -= 1;
+			self.subscriber.try_recv()
+		}
+		else {
+			None
+		}
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let subscriber: *mut Subscriber<T> = func_ptr as *mut Subscriber<T>;
+			let data = (*subscriber).subscriber.data.get();
+
+			if epoll_events & syscalls::EPOLLIN != 0 {
+				let buffer = [0, ..8];
+
+				let ret = helpers::retry(||
+					libc::read((*data).epoll_fd,
+						       buffer.as_ptr() as *mut libc::c_void,
+						       buffer.len() as libc::size_t) as i32
+				);
+
+				if ret == 8 {
+					(*data).mutex.lock();
+					if (*data).lagged > 0 {
+						event_queue.push_back_event(events::Event {
+							event_type: events::ChannelLaggedEvent((*data).lagged),
+							is_valid: true,
+							source_info: (*subscriber).event_source_info.clone()
+						});
+						(*data).lagged = 0;
+					}
+					// Dropped messages might have been announced already
+					let available = cmp::min((*subscriber).available_messages, (*data).queue.len());
+					let new_messages = (*data).queue.len() - available;
+					(*subscriber).available_messages = (*data).queue.len();
+					for _ in range(0, new_messages) {
+						event_queue.push_back_event(events::Event {
+							event_type: events::ChannelMessageEvent,
+							is_valid: true,
+							source_info: (*subscriber).event_source_info.clone()
+						});
+					}
+					if (*data).closed && !(*subscriber).closed_reported {
+						(*subscriber).closed_reported = true;
+						event_queue.push_back_event(events::Event {
+							event_type: events::ChannelClosedEvent,
+							is_valid: true,
+							source_info: (*subscriber).event_source_info.clone()
+						});
+					}
+					(*data).receiver_notified = false;
+					(*data).mutex.unlock();
+				}
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for Subscriber<T> {
+	fn drop(&mut self) {
+		let data = self.subscriber.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			if (*data).epoll_fd != -1 {
+				libc::close((*data).epoll_fd);
+				(*data).epoll_fd = -1;
+			}
+			(*data).mutex.unlock();
+		}
+		self.remove_pending_events();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use super::{Broadcast, Subscriber};
+
+	#[test]
+	fn every_subscriber_gets_a_copy() {
+		let mut queue = EventQueue::new();
+		let publisher = Broadcast::<int>::create(4);
+		let first = publisher.subscribe();
+		let mut second = Subscriber::from_blocking_subscriber(publisher.subscribe(), &queue);
+		assert_eq!(publisher.send(1), 2);
+		assert_eq!(first.recv(), Some(1));
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::ChannelMessageEvent => {},
+			_ => fail!("Expected a ChannelMessageEvent")
+		}
+		assert!(event.originates_from(second));
+		assert_eq!(second.recv(), Some(1));
+		assert!(first.try_recv().is_none());
+		assert!(second.recv().is_none());
+	}
+
+	#[test]
+	fn slow_subscriber_skips_the_oldest_messages() {
+		let mut queue = EventQueue::new();
+		let publisher = Broadcast::<int>::create(2);
+		let mut subscriber = Subscriber::from_blocking_subscriber(publisher.subscribe(), &queue);
+		for i in range(1, 6) {
+			publisher.send(i);
+		}
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::ChannelLaggedEvent(3) => {},
+			_ => fail!("Expected a ChannelLaggedEvent for 3 messages")
+		}
+		for _ in range(0, 2) {
+			match queue.next_event().unwrap().event_type {
+				events::ChannelMessageEvent => {},
+				_ => fail!("Expected a ChannelMessageEvent")
+			}
+		}
+		assert_eq!(subscriber.recv(), Some(4));
+		assert_eq!(subscriber.recv(), Some(5));
+		assert!(subscriber.recv().is_none());
+	}
+
+	#[test]
+	fn blocking_subscriber_counts_lagged_messages() {
+		let publisher = Broadcast::<int>::create(1);
+		let subscriber = publisher.subscribe();
+		publisher.send(1);
+		publisher.send(2);
+		assert_eq!(subscriber.take_lagged(), 1);
+		assert_eq!(subscriber.take_lagged(), 0);
+		assert_eq!(subscriber.recv(), Some(2));
+	}
+
+	#[test]
+	fn late_subscriber_only_gets_new_messages() {
+		let publisher = Broadcast::<int>::create(4);
+		assert_eq!(publisher.send(1), 0);
+		let subscriber = publisher.subscribe();
+		assert!(subscriber.try_recv().is_none());
+		assert_eq!(publisher.send(2), 1);
+		assert_eq!(subscriber.try_recv(), Some(2));
+		assert!(subscriber.try_recv().is_none());
+	}
+
+	#[test]
+	fn closed_event_when_publishers_are_gone() {
+		let mut queue = EventQueue::new();
+		let publisher = Broadcast::<int>::create(4);
+		let other = publisher.clone();
+		let subscriber = Subscriber::from_blocking_subscriber(publisher.subscribe(), &queue);
+		drop(publisher);
+		assert_eq!(other.subscriber_count(), 1);
+		drop(other);
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::ChannelClosedEvent => {},
+			_ => fail!("Expected a ChannelClosedEvent")
+		}
+		assert!(event.originates_from(subscriber));
+	}
+}
@@ -9,8 +9,11 @@
 // except according to those terms.
 
 use std::cast;
+use std::cmp;
+use std::i32;
 use std::libc;
-use std::cell::RefCell;
+use std::util;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::unstable::mutex::Mutex;
 use std::sync::arc::UnsafeArc;
@@ -25,96 +28,466 @@ use super::syscalls;
 use super::helpers;
 
 pub struct BlockingReceiver<T> {
-	priv data: UnsafeArc<SharedChannelData<T>>
+	priv data: UnsafeArc<SharedChannelData<T>>,
+	// eventfd for recv_deadline. Created on first use.
+	priv wait_fd: Cell<i32>
 }
 
 pub struct Transmitter<T> {
 	priv data: UnsafeArc<SharedChannelData<T>>
 }
 
+/// All fields except space_mutex are protected by the mutex
 struct SharedChannelData<T> {
 	queue: RingBuf<T>,
 	mutex: Mutex,
 	port_alive: bool,
 	nr_senders: uint,
 	receiver_notified: bool,
-	epoll_fd: i32
+	epoll_fd: i32,
+	// eventfds of evented Senders that are notified when the receiver is closed
+	sender_fds: ~[i32],
+	// eventfds of evented Senders that got Full and wait for free space
+	blocked_sender_fds: ~[i32],
+	capacity: uint, // 0 for unbounded channels
+	// Senders that wait for free space block on this mutex
+	space_mutex: Mutex,
+	waiting_senders: uint,
+	// Cumulative number of messages that were sent and received
+	total_sent: uint,
+	total_received: uint
 }
 
 pub struct Channel<T>;
 
 impl <T:Send> Channel<T> {
 	pub fn create_blocking() -> (BlockingReceiver<T>, Transmitter<T>) {
+		Channel::<T>::create_shared(0)
+	}
+
+	pub fn create(event_queue: &EventQueue) -> (~Receiver<T>, Transmitter<T>) {
+		let (rx,tx) = Channel::<T>::create_blocking();
+		(Receiver::from_blocking_receiver(rx, event_queue), tx)
+	}
+
+	/**
+	 * Creates a channel that holds at most `capacity` messages.
+	 * `try_send` fails with `Full` and `send` blocks while the channel is full.
+	 */
+	pub fn create_bounded_blocking(capacity: uint) -> (BlockingReceiver<T>, Transmitter<T>) {
+		if capacity == 0 {
+			fail!("Channel capacity must be greater than 0");
+		}
+		Channel::<T>::create_shared(capacity)
+	}
+
+	pub fn create_bounded(capacity: uint, event_queue: &EventQueue) -> (~Receiver<T>, Transmitter<T>) {
+		let (rx,tx) = Channel::<T>::create_bounded_blocking(capacity);
+		(Receiver::from_blocking_receiver(rx, event_queue), tx)
+	}
+
+	fn create_shared(capacity: uint) -> (BlockingReceiver<T>, Transmitter<T>) {
 		let shared_data: UnsafeArc<SharedChannelData<T>> 
 			= UnsafeArc::new(SharedChannelData {
 				queue: RingBuf::new(),
 				mutex: unsafe { Mutex::new() },
 				port_alive: true,
-				receiver_notified: false,
 				nr_senders: 1,
-				epoll_fd: -1
+				receiver_notified: false,
+				epoll_fd: -1,
+				sender_fds: ~[],
+				blocked_sender_fds: ~[],
+				capacity: capacity,
+				space_mutex: unsafe { Mutex::new() },
+				waiting_senders: 0,
+				total_sent: 0,
+				total_received: 0
 		});
-		(BlockingReceiver{data: shared_data.clone()}, Transmitter{data: shared_data})
+		(BlockingReceiver{data: shared_data.clone(), wait_fd: Cell::new(-1)}, Transmitter{data: shared_data})
 	}
+}
 
-	pub fn create(event_queue: &EventQueue) -> (~Receiver<T>, Transmitter<T>) {
-		let (rx,tx) = Channel::<T>::create_blocking();
-		(Receiver::from_blocking_receiver(rx, event_queue), tx)
+/**
+ * Wakes the receiver unless it was already notified since it last looked
+ * at the queue. Must be called with the data mutex held.
+ */
+unsafe fn notify_receiver<T>(data: *mut SharedChannelData<T>) {
+	if (*data).receiver_notified { return; }
+	(*data).receiver_notified = true;
+
+	// Decide depending on port state what to do
+	if (*data).epoll_fd == -1 {
+		(*data).mutex.signal();
+	}
+	else if helpers::signal_eventfd((*data).epoll_fd) == -1 {
+		(*data).mutex.unlock();
+		fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
+	}
+}
+
+/**
+ * Wakes up to `count` senders that are blocked in `send` on a full channel.
+ * Must be called without holding the data mutex.
+ */
+unsafe fn wake_senders<T>(data: *mut SharedChannelData<T>, count: uint) {
+	if count == 0 { return; }
+	(*data).space_mutex.lock();
+	for _ in range(0, count) {
+		(*data).space_mutex.signal();
+	}
+	(*data).space_mutex.unlock();
+}
+
+/**
+ * Calls `f` with the data mutex held and afterwards wakes the senders that
+ * wait for space. If `reset_notification` is set the next message notifies
+ * the receiver again.
+ */
+unsafe fn receive<T, U>(data: *mut SharedChannelData<T>, reset_notification: bool,
+                        f: |*mut SharedChannelData<T>| -> U) -> U {
+	(*data).mutex.lock();
+	if reset_notification {
+		(*data).receiver_notified = false;
+	}
+	let ret = f(data);
+	let waiting = (*data).waiting_senders;
+	(*data).mutex.unlock();
+	wake_senders(data, waiting);
+	ret
+}
+
+/// Removes the first message. Must be called with the data mutex held.
+unsafe fn pop_message<T>(data: *mut SharedChannelData<T>) -> Option<T> {
+	let was_full = (*data).capacity != 0 && (*data).queue.len() >= (*data).capacity;
+	let ret = (*data).queue.pop_front();
+	if ret.is_some() {
+		(*data).total_received += 1;
+		if was_full {
+			notify_blocked_senders(data);
+		}
+	}
+	ret
+}
+
+/**
+ * Moves up to `max` messages into `buf` and returns their number.
+ * Must be called with the data mutex held.
+ */
+unsafe fn pop_messages<T>(data: *mut SharedChannelData<T>, buf: &mut ~[T], max: uint) -> uint {
+	let count = cmp::min((*data).queue.len(), max);
+	if count == 0 { return 0; }
+	let was_full = (*data).capacity != 0 && (*data).queue.len() >= (*data).capacity;
+	buf.reserve_additional(count);
+	for _ in range(0, count) {
+		buf.push((*data).queue.pop_front().unwrap());
+	}
+	(*data).total_received += count;
+	if was_full {
+		notify_blocked_senders(data);
+	}
+	count
+}
+
+/// Wakes all evented Senders. Must be called with the mutex held.
+unsafe fn notify_senders<T>(data: *mut SharedChannelData<T>) {
+	for fd in (*data).sender_fds.iter() {
+		signal_sender(data, *fd);
+	}
+	(*data).blocked_sender_fds.clear();
+}
+
+/// Wakes the evented Senders that got Full. Must be called with the mutex held.
+unsafe fn notify_blocked_senders<T>(data: *mut SharedChannelData<T>) {
+	for fd in (*data).blocked_sender_fds.iter() {
+		signal_sender(data, *fd);
+	}
+	(*data).blocked_sender_fds.clear();
+}
+
+unsafe fn signal_sender<T>(data: *mut SharedChannelData<T>, fd: i32) {
+	if helpers::signal_eventfd(fd) == -1 {
+		(*data).mutex.unlock();
+		fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
+	}
+}
+
+impl<T:Send> SharedChannelData<T> {
+	/// Calls `f` with the mutex held
+	fn locked<U>(&mut self, f: |&mut SharedChannelData<T>| -> U) -> U {
+		unsafe { self.mutex.lock(); }
+		let ret = f(self);
+		unsafe { self.mutex.unlock(); }
+		ret
+	}
+
+	fn len(&mut self) -> uint { self.locked(|d| d.queue.len()) }
+	fn sender_count(&mut self) -> uint { self.locked(|d| d.nr_senders) }
+	fn is_receiver_alive(&mut self) -> bool { self.locked(|d| d.port_alive) }
+	fn sent_count(&mut self) -> uint { self.locked(|d| d.total_sent) }
+	fn received_count(&mut self) -> uint { self.locked(|d| d.total_received) }
+}
+
+impl<T:Send> BlockingReceiver<T> {
+	/// Returns the number of messages that were sent and not yet received
+	pub fn len(&self) -> uint {
+		unsafe { (*self.data.get()).len() }
+	}
+
+	/// Returns the number of live Transmitters
+	pub fn sender_count(&self) -> uint {
+		unsafe { (*self.data.get()).sender_count() }
+	}
+
+	/// Returns the number of messages that were sent since the channel was created
+	pub fn sent_count(&self) -> uint {
+		unsafe { (*self.data.get()).sent_count() }
+	}
+
+	/// Returns the number of messages that were received since the channel was created
+	pub fn received_count(&self) -> uint {
+		unsafe { (*self.data.get()).received_count() }
+	}
+}
+
+impl<T:Send> Transmitter<T> {
+	/// See `BlockingReceiver::len`
+	pub fn len(&self) -> uint {
+		unsafe { (*self.data.get()).len() }
+	}
+
+	/// See `BlockingReceiver::sender_count`
+	pub fn sender_count(&self) -> uint {
+		unsafe { (*self.data.get()).sender_count() }
+	}
+
+	pub fn is_receiver_alive(&self) -> bool {
+		unsafe { (*self.data.get()).is_receiver_alive() }
+	}
+
+	/// See `BlockingReceiver::sent_count`
+	pub fn sent_count(&self) -> uint {
+		unsafe { (*self.data.get()).sent_count() }
+	}
+
+	/// See `BlockingReceiver::received_count`
+	pub fn received_count(&self) -> uint {
+		unsafe { (*self.data.get()).received_count() }
 	}
 }
 
 impl<T:Send> BlockingReceiver<T> {
 	pub fn recv(&self) -> T {
+		match self.recv_opt() {
+			Some(t) => t,
+			None => fail!("Remote channels are dead") // Sender(s) dead
+		}
+	}
+
+	pub fn recv_opt(&self) -> Option<T> {
 		let data = self.data.get();
 		unsafe {
-			(*data).mutex.lock();
-			while ((*data).queue.len() < 1) && ((*data).nr_senders != 0) {
-				(*data).mutex.wait();
-			}
-			if (*data).queue.len() > 0 {
-				let ret = (*data).queue.pop_front().unwrap();
-				(*data).receiver_notified = false;
-				(*data).mutex.unlock();
-				ret
+			receive(data, true, |data| {
+				while (*data).queue.len() == 0 && (*data).nr_senders != 0 {
+					(*data).mutex.wait();
+					(*data).receiver_notified = false;
+				}
+				pop_message(data)
+			})
+		}
+	}
+
+	/**
+	 * Moves up to `max` available messages into `buf` without blocking
+	 * and returns their number.
+	 */
+	pub fn recv_batch(&self, buf: &mut ~[T], max: uint) -> uint {
+		let data = self.data.get();
+		unsafe {
+			receive(data, true, |data| pop_messages(data, buf, max))
+		}
+	}
+
+	pub fn try_recv(&self) -> TryRecvResult<T> {
+		let data = self.data.get();
+		unsafe {
+			receive(data, true, |data| {
+				match pop_message(data) {
+					Some(t) => Data(t),
+					None if (*data).nr_senders == 0 => Disconnected,
+					None => Empty
+				}
+			})
+		}
+	}
+}
+impl<T:Send> BlockingReceiver<T> {
+	/**
+	 * Waits at most `timeout` milliseconds for a message.
+	 * Returns Empty if the timeout expired.
+	 */
+	pub fn recv_timeout(&self, timeout: u32) -> TryRecvResult<T> {
+		self.recv_deadline(helpers::monotonic_time_ns() + timeout as u64 * 1000000)
+	}
+
+	/**
+	 * Waits for a message until the deadline has passed.
+	 * The deadline is in nanoseconds of the monotonic clock
+	 * which is also used by extra::time::precise_time_ns.
+	 * Returns Empty if the deadline passed.
+	 */
+	pub fn recv_deadline(&self, deadline: u64) -> TryRecvResult<T> {
+		if self.wait_fd.get() == -1 {
+			self.wait_fd.set(create_wait_fd());
+		}
+		loop {
+			match self.try_recv() {
+				Empty => {},
+				ret => return ret
 			}
-			else { // Sender(s) dead
-				(*data).mutex.unlock();
-				fail!("Remote channels are dead");
+			if wait_ready(&[self as &Selectable], self.wait_fd.get(), Some(deadline)).is_none() {
+				return self.try_recv();
 			}
 		}
 	}
+}
 
-	pub fn recv_opt(&self) -> Option<T> {
+/// A receiver that can be waited for by Select
+trait Selectable {
+	/// Redirects notifications to the eventfd. Returns true if the receiver is ready.
+	fn arm(&self, fd: i32) -> bool;
+	/// Switches back to condition variable notifications. Returns true if the receiver is ready.
+	fn disarm(&self) -> bool;
+}
+
+impl<T:Send> Selectable for BlockingReceiver<T> {
+	fn arm(&self, fd: i32) -> bool {
 		let data = self.data.get();
-		let mut ret = None;
 		unsafe {
 			(*data).mutex.lock();
-			while ((*data).queue.len() < 1) && ((*data).nr_senders != 0) {
-				(*data).mutex.wait();
-			}
-			if (*data).queue.len() > 0 {
-				ret = Some((*data).queue.pop_front().unwrap());
-			}
+			(*data).epoll_fd = fd;
 			(*data).receiver_notified = false;
+			let ready = (*data).queue.len() > 0 || (*data).nr_senders == 0;
 			(*data).mutex.unlock();
-			ret						
+			ready
 		}
 	}
 
-	pub fn try_recv(&self) -> TryRecvResult<T> {
+	fn disarm(&self) -> bool {
 		let data = self.data.get();
-		let mut ret = Empty;
 		unsafe {
 			(*data).mutex.lock();
-			if (*data).queue.len() >= 1 {
-				ret = Data((*data).queue.pop_front().unwrap());
+			(*data).epoll_fd = -1;
+			let ready = (*data).queue.len() > 0 || (*data).nr_senders == 0;
+			(*data).mutex.unlock();
+			ready
+		}
+	}
+}
+
+/**
+ * Waits on several BlockingReceivers at once.
+ * A receiver is ready when it has a message or all of its senders are gone.
+ * Receivers must not be used by other threads while a wait is in progress.
+ */
+pub struct Select<'a> {
+	priv receivers: ~[&'a Selectable],
+	priv fd: i32
+}
+
+impl<'a> Select<'a> {
+	pub fn new() -> Select<'a> {
+		Select {
+			receivers: ~[],
+			fd: create_wait_fd()
+		}
+	}
+
+	/// Adds a receiver and returns the index that `wait` returns for it
+	pub fn add<T:Send>(&mut self, receiver: &'a BlockingReceiver<T>) -> uint {
+		self.receivers.push(receiver as &'a Selectable);
+		self.receivers.len() - 1
+	}
+
+	/// Blocks until a receiver is ready and returns its index
+	pub fn wait(&self) -> uint {
+		self.wait_internal(None).unwrap()
+	}
+
+	/// Returns None if no receiver got ready within `timeout` milliseconds
+	pub fn wait_timeout(&self, timeout: u32) -> Option<uint> {
+		self.wait_internal(Some(helpers::monotonic_time_ns() + timeout as u64 * 1000000))
+	}
+
+	/// Like wait_timeout with an absolute deadline as in `recv_deadline`
+	pub fn wait_deadline(&self, deadline: u64) -> Option<uint> {
+		self.wait_internal(Some(deadline))
+	}
+
+	fn wait_internal(&self, deadline: Option<u64>) -> Option<uint> {
+		if self.receivers.len() == 0 {
+			fail!("Select without receivers");
+		}
+		wait_ready(self.receivers.as_slice(), self.fd, deadline)
+	}
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for Select<'a> {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.fd); }
+	}
+}
+
+fn create_wait_fd() -> i32 {
+	let fd = unsafe { syscalls::eventfd(0, syscalls::EFD_NONBLOCK) };
+	if fd == -1 {
+		fail!("Creating eventfd for select failed: {}", helpers::last_error().desc);
+	}
+	fd
+}
+
+/**
+ * Waits until one of the receivers is ready or the deadline passed and
+ * returns the index of the first ready receiver.
+ * `fd` must be a non-blocking eventfd that is only used by one wait at a time.
+ */
+fn wait_ready(receivers: &[&Selectable], fd: i32, deadline: Option<u64>) -> Option<uint> {
+	let mut ret = None;
+	loop {
+		// Clear wakeups of earlier waits
+		let buffer = [0u8, ..8];
+		unsafe {
+			libc::read(fd, buffer.as_ptr() as *mut libc::c_void, buffer.len() as libc::size_t);
+		}
+
+		let mut ready = false;
+		for receiver in receivers.iter() {
+			ready = receiver.arm(fd) || ready;
+		}
+
+		let timeout = match deadline {
+			None => -1,
+			Some(deadline) => {
+				let now = helpers::monotonic_time_ns();
+				if now >= deadline { 0 }
+				else { cmp::min((deadline - now + 999999) / 1000000, i32::max_value as u64) as i32 }
 			}
-			else if (*data).nr_senders == 0 {
-				ret = Disconnected;
+		};
+		if !ready && timeout != 0 {
+			let mut pfd = syscalls::pollfd { fd: fd, events: syscalls::POLLIN, revents: 0 };
+			let res = helpers::retry(|| unsafe { syscalls::poll(&mut pfd, 1, timeout) });
+			if res == -1 {
+				fail!("Error on polling eventfd: {}", helpers::last_error().desc);
 			}
-			(*data).receiver_notified = false;
-			(*data).mutex.unlock();
-			ret						
+		}
+
+		for (i, receiver) in receivers.iter().enumerate() {
+			if receiver.disarm() && ret.is_none() {
+				ret = Some(i);
+			}
+		}
+		if ret.is_some() || timeout == 0 {
+			return ret;
 		}
 	}
 }
@@ -126,8 +499,15 @@ impl<T:Send> Drop for BlockingReceiver<T> {
 		unsafe {
 			(*data).mutex.lock();
 			(*data).port_alive = false;
-			(*data).queue.clear();
+			// The messages are dropped after the mutex was released
+			let _queue = util::replace(&mut (*data).queue, RingBuf::new());
+			notify_senders(data);
+			let waiting = (*data).waiting_senders;
 			(*data).mutex.unlock();
+			wake_senders(data, waiting);
+			if self.wait_fd.get() != -1 {
+				libc::close(self.wait_fd.get());
+			}
 		}
 	}
 }
@@ -138,13 +518,28 @@ pub enum TryRecvResult<T> {
     Data(T),
 }
 
+pub enum TrySendError<T> {
+	/// The channel is bounded and full. Contains the message that couldn't be sent.
+	Full(T),
+	/// The receiver is gone. Contains the message that couldn't be sent.
+	Closed(T)
+}
+
+/// Returned by `send` when the receiver is gone. Contains the unsent message.
+pub struct SendError<T>(T);
+
 pub struct Receiver<T> {
 	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
-	priv receiver: BlockingReceiver<T>,
+	// Only None after the receiver was taken by into_blocking
+	priv receiver: Option<BlockingReceiver<T>>,
 	priv event_queue: Rc<RefCell<EventQueueImpl>>,
 	priv event_source_info: Rc<events::EventSourceInfo>,
 	priv epoll_events: u32,
-	priv available_messages: uint
+	priv available_messages: uint,
+	priv coalesce_messages: bool,
+	priv high_water: uint, // 0 if disabled
+	priv low_water: uint,
+	priv above_high_water: bool
 }
 
 impl<T> events::EventSource for Receiver<T> {
@@ -155,16 +550,24 @@ impl<T> events::EventSource for Receiver<T> {
 
 impl<T:Send> Receiver<T> {
 	pub fn from_blocking_receiver(blocking_receiver: BlockingReceiver<T>, event_queue: &EventQueue) -> ~Receiver<T> {
-		let receiver = ~Receiver{
-			receiver: blocking_receiver,
+		Receiver::attach(blocking_receiver, event_queue, false)
+	}
+
+	fn attach(blocking_receiver: BlockingReceiver<T>, event_queue: &EventQueue, coalesce_messages: bool) -> ~Receiver<T> {
+		let mut receiver = ~Receiver{
+			receiver: Some(blocking_receiver),
 			event_queue: event_queue._get_impl(),
 			process_func: Receiver::<T>::process_epoll_events,
 			event_source_info: Rc::new(events::EventSourceInfo::new()),
 			epoll_events: 0,
-			available_messages: 0
+			available_messages: 0,
+			coalesce_messages: coalesce_messages,
+			high_water: 0,
+			low_water: 0,
+			above_high_water: false
 		};
 
-		let data = receiver.receiver.data.get();
+		let data = receiver.data();
 		unsafe {
 			(*data).mutex.lock();
 
@@ -179,29 +582,18 @@ impl<T:Send> Receiver<T> {
 				receiver.event_queue.borrow().with_mut(|q|
 					q.register_fd(fd, syscalls::EPOLLIN, callback)
 				);
-				// Check if there are messages available and if yes queue them
+				// Check if there are messages available and if yes queue them.
+				// A notification for the blocking receiver might be outstanding.
+				(*data).receiver_notified = false;
 				if (*data).queue.len() > 0 {
-					let bytes = [0,..8];
-					let content: *mut u64 = cast::transmute(&bytes);
-					*content = 1;
-					let ret = helpers::retry(||
-						libc::write(fd, bytes.as_ptr() as *libc::c_void, 8) as libc::c_int
-					);
-					if ret == -1 {
-						(*data).mutex.unlock();
-						fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
-					}
-					(*data).receiver_notified = true;
+					notify_receiver(data);
 				}
 			}
 			else {
-				receiver.event_queue.borrow().with_mut(|q|
-					q.push_back_event(events::Event{
-						event_type: events::ChannelClosedEvent,
-						is_valid: true,
-						source_info: receiver.event_source_info.clone()
-					})
-				);
+				// Announce the messages that are left before the ChannelClosedEvent
+				let len = (*data).queue.len();
+				let event_queue = receiver.event_queue.clone();
+				event_queue.borrow().with_mut(|q| receiver.announce(q, len, false));
 			}
 
 			(*data).mutex.unlock();
@@ -211,19 +603,167 @@ impl<T:Send> Receiver<T> {
 
 	pub fn recv(&mut self) -> Option<T> {
 		if self.available_messages > 0 {
-			let ret = self.receiver.recv();
+			let data = self.data();
 			self.available_messages -= 1;
-			Some(ret)
+			let ret = unsafe { receive(data, false, |data| pop_message(data)) };
+			self.check_low_water();
+			ret
 		}
 		else {
 			None
 		}
 	}
 
+	/**
+	 * Moves up to `max` messages that were announced by events into `buf`
+	 * and returns their number.
+	 */
+	pub fn recv_batch(&mut self, buf: &mut ~[T], max: uint) -> uint {
+		let data = self.data();
+		let max = cmp::min(max, self.available_messages);
+		let count = unsafe { receive(data, false, |data| pop_messages(data, buf, max)) };
+		self.available_messages -= count;
+		self.check_low_water();
+		count
+	}
+
+	/**
+	 * If enabled a single ChannelMessagesEvent with the number of new
+	 * messages is queued instead of a ChannelMessageEvent per message.
+	 */
+	pub fn set_coalesce_messages(&mut self, coalesce: bool) {
+		self.coalesce_messages = coalesce;
+	}
+
+	pub fn get_coalesce_messages(&self) -> bool {
+		self.coalesce_messages
+	}
+
+	/**
+	 * Queues a ChannelHighWaterEvent when the number of pending messages
+	 * reaches `high` and afterwards a ChannelLowWaterEvent when it dropped to
+	 * `low`. Both events contain the number of pending messages.
+	 * A `high` value of 0 disables the alarms. For bounded channels `high`
+	 * must not exceed the capacity, because `try_send` fails with `Full`
+	 * before the queue could grow beyond it.
+	 */
+	pub fn set_water_marks(&mut self, high: uint, low: uint) {
+		if high != 0 && low >= high {
+			fail!("The low water mark must be below the high water mark");
+		}
+		let capacity = unsafe { (*self.data()).capacity };
+		if capacity != 0 && high > capacity {
+			fail!("The high water mark must not exceed the channel capacity");
+		}
+		self.high_water = high;
+		self.low_water = low;
+		self.above_high_water = false;
+		// The queue might already be above the new mark
+		let len = self.len();
+		let event_queue = self.event_queue.clone();
+		event_queue.borrow().with_mut(|q| self.check_water_marks(q, len));
+	}
+
+	pub fn get_water_marks(&self) -> (uint, uint) {
+		(self.high_water, self.low_water)
+	}
+
+	pub fn len(&self) -> uint {
+		self.receiver.get_ref().len()
+	}
+
+	pub fn sender_count(&self) -> uint {
+		self.receiver.get_ref().sender_count()
+	}
+
+	pub fn sent_count(&self) -> uint {
+		self.receiver.get_ref().sent_count()
+	}
+
+	pub fn received_count(&self) -> uint {
+		self.receiver.get_ref().received_count()
+	}
+
+	fn check_water_marks(&mut self, event_queue: &mut EventQueueImpl, len: uint) {
+		if self.high_water == 0 { return; }
+		let event_type = if !self.above_high_water && len >= self.high_water {
+			self.above_high_water = true;
+			events::ChannelHighWaterEvent(len)
+		}
+		else if self.above_high_water && len <= self.low_water {
+			self.above_high_water = false;
+			events::ChannelLowWaterEvent(len)
+		}
+		else { return; };
+		event_queue.push_back_event(events::Event {
+			event_type: event_type,
+			is_valid: true,
+			source_info: self.event_source_info.clone()
+		});
+	}
+
+	/// Called after receiving, when the queue can only have become shorter
+	fn check_low_water(&mut self) {
+		if !self.above_high_water { return; }
+		let len = self.len();
+		let event_queue = self.event_queue.clone();
+		event_queue.borrow().with_mut(|q| self.check_water_marks(q, len));
+	}
+
+	/**
+	 * Turns the Receiver back into a BlockingReceiver.
+	 * Messages that were not received yet stay in the channel.
+	 */
+	pub fn into_blocking(~self) -> BlockingReceiver<T> {
+		let mut receiver = self;
+		receiver.detach();
+		receiver.receiver.take().unwrap()
+	}
+
+	/**
+	 * Moves the Receiver to another EventQueue. Pending events are removed
+	 * from the old queue and are queued again on the new one for all
+	 * messages that were not received yet, as well as the
+	 * ChannelClosedEvent if all senders are gone.
+	 * Message coalescing and the water marks are kept.
+	 */
+	pub fn rebind(~self, event_queue: &EventQueue) -> ~Receiver<T> {
+		let coalesce_messages = self.coalesce_messages;
+		let (high_water, low_water) = (self.high_water, self.low_water);
+		let mut receiver = Receiver::attach(self.into_blocking(), event_queue, coalesce_messages);
+		if high_water != 0 {
+			receiver.set_water_marks(high_water, low_water);
+		}
+		receiver
+	}
+
+	fn data(&self) -> *mut SharedChannelData<T> {
+		self.receiver.get_ref().data.get()
+	}
+
+	/// Stops notifications through the eventfd and forgets all announced messages
+	fn detach(&mut self) {
+		let data = self.data();
+		unsafe {
+			(*data).mutex.lock();
+			if (*data).epoll_fd != -1 {
+				let fd = (*data).epoll_fd;
+				self.event_queue.borrow().with_mut(|q| q.unregister_fd(fd));
+				libc::close(fd);
+				(*data).epoll_fd = -1; // Disable further events from clients
+			}
+			// A blocking receiver must get the next notification
+			(*data).receiver_notified = false;
+			(*data).mutex.unlock();
+		}
+		self.available_messages = 0;
+		self.remove_pending_events();
+	}
+
 	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
 		unsafe {
 			let receiver: *mut Receiver<T> = func_ptr as *mut Receiver<T>;
-			let data = (*receiver).receiver.data.get();
+			let data = (*receiver).data();
 
 			if epoll_events & syscalls::EPOLLIN != 0 {
 				let buffer = [0, ..8];
@@ -235,35 +775,55 @@ impl<T:Send> Receiver<T> {
 				);
 
 				if ret == 8 { // Must be 8 bytes
-					let value: *u64 = cast::transmute(&buffer);
-					if *value == 1 {
-						(*data).mutex.lock();
-						let new_messages = (*data).queue.len() - (*receiver).available_messages;
-						(*receiver).available_messages += (*data).queue.len();
-						for _ in range(0, new_messages) {
-							let e = events::Event {
-								event_type: events::ChannelMessageEvent,
-								is_valid: true,
-								source_info: (*receiver).event_source_info.clone()
-							};
-							event_queue.push_back_event(e);
-						}
-						if (*data).nr_senders == 0 {
-							let e = events::Event {
-								event_type: events::ChannelClosedEvent,
-								is_valid: true,
-								source_info: (*receiver).event_source_info.clone()
-							};
-							event_queue.push_back_event(e);
-						}
-						(*data).receiver_notified = false;
-						(*data).mutex.unlock();
-					}
+					(*data).mutex.lock();
+					(*data).receiver_notified = false;
+					let senders_alive = (*data).nr_senders != 0;
+					let len = (*data).queue.len();
+					(*data).mutex.unlock();
+					(*receiver).announce(event_queue, len, senders_alive);
 				}
 			}	
 		}
 	}
 
+	/**
+	 * Queues the events for the messages that arrived since the last call
+	 * and the ChannelClosedEvent if all senders are gone.
+	 */
+	fn announce(&mut self, event_queue: &mut EventQueueImpl, len: uint, senders_alive: bool) {
+		let new_messages = len - self.available_messages;
+		self.available_messages = len;
+		self.check_water_marks(event_queue, len);
+		if self.coalesce_messages {
+			if new_messages > 0 {
+				let e = events::Event {
+					event_type: events::ChannelMessagesEvent(new_messages),
+					is_valid: true,
+					source_info: self.event_source_info.clone()
+				};
+				event_queue.push_back_event(e);
+			}
+		}
+		else {
+			for _ in range(0, new_messages) {
+				let e = events::Event {
+					event_type: events::ChannelMessageEvent,
+					is_valid: true,
+					source_info: self.event_source_info.clone()
+				};
+				event_queue.push_back_event(e);
+			}
+		}
+		if !senders_alive {
+			let e = events::Event {
+				event_type: events::ChannelClosedEvent,
+				is_valid: true,
+				source_info: self.event_source_info.clone()
+			};
+			event_queue.push_back_event(e);
+		}
+	}
+
 	fn remove_pending_events(&mut self) {
 		self.event_queue.borrow().with_mut(|q|
 			q.remove_pending_events(
@@ -275,56 +835,77 @@ impl<T:Send> Receiver<T> {
 #[unsafe_destructor]
 impl<T:Send> Drop for Receiver<T> {
 	fn drop(&mut self) {
-		let data = self.receiver.data.get();
-		unsafe {
-			(*data).mutex.lock();
-			if (*data).epoll_fd != -1 {
-				libc::close((*data).epoll_fd);
-				(*data).epoll_fd = -1; // Disable further events from clients
-			}
-			(*data).mutex.unlock();
+		if self.receiver.is_some() {
+			self.detach();
 		}
-		self.remove_pending_events();
 	}
 }
 
 impl<T:Send> Transmitter<T> {
 
-	pub fn send(&self, t: T) {
-		self.try_send(t);
+	/**
+	 * Sends a message. Blocks while a bounded channel is full.
+	 * Hands the message back if the receiver is gone.
+	 */
+	pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+		let mut t = t;
+		loop {
+			match self.try_send(t) {
+				Ok(()) => return Ok(()),
+				Err(Closed(msg)) => return Err(SendError(msg)),
+				Err(Full(msg)) => {
+					t = msg;
+					self.wait_for_space();
+				}
+			}
+		}
+	}
+
+	/**
+	 * Returns true if the receiver is gone. Every later send fails and
+	 * hands the message back.
+	 */
+	pub fn is_closed(&self) -> bool {
+		!self.is_receiver_alive()
 	}
 
-	pub fn try_send(&self, t: T) -> bool {
+	fn wait_for_space(&self) {
+		let data = self.data.get();
+		unsafe {
+			// The receiver can't signal between the check and the wait
+			// because it needs the space mutex for that
+			(*data).space_mutex.lock();
+			(*data).mutex.lock();
+			if (*data).port_alive && (*data).queue.len() >= (*data).capacity {
+				(*data).waiting_senders += 1;
+				(*data).mutex.unlock();
+				(*data).space_mutex.wait();
+				(*data).mutex.lock();
+				(*data).waiting_senders -= 1;
+			}
+			(*data).mutex.unlock();
+			(*data).space_mutex.unlock();
+		}
+	}
+
+	pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
 		let data = self.data.get();
 		unsafe {
 			(*data).mutex.lock();
 			if !(*data).port_alive {
 				(*data).mutex.unlock();
-				return false; 
+				return Err(Closed(t));
 			}
-			(*data).queue.push_back(t);
-			if (*data).queue.len() == 1 && !(*data).receiver_notified { // Signal to receiver
-				// Decide depending on port state what to do
-				if (*data).epoll_fd == -1 {
-					(*data).mutex.signal();
-				}
-				else {
-					let bytes = [0,..8];
-					let content: *mut u64 = cast::transmute(&bytes);
-					*content = 1;
-					let ret = helpers::retry(||
-						libc::write((*data).epoll_fd, bytes.as_ptr() as *libc::c_void, 8) as libc::c_int
-					);
-					if ret == -1 {
-						(*data).mutex.unlock();
-						fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
-					}
-				}
-				(*data).receiver_notified = true;
+			if (*data).capacity != 0 && (*data).queue.len() >= (*data).capacity {
+				(*data).mutex.unlock();
+				return Err(Full(t));
 			}
-			(*data).mutex.unlock();			
+			(*data).queue.push_back(t);
+			(*data).total_sent += 1;
+			notify_receiver(data);
+			(*data).mutex.unlock();
 		}
-		true
+		Ok(())
 	}
 }
 
@@ -337,24 +918,8 @@ impl<T:Send> Drop for Transmitter<T> {
 		unsafe {
 			(*data).mutex.lock();
 			(*data).nr_senders -= 1;
-			if (*data).nr_senders == 0 && !(*data).receiver_notified {
-				if (*data).epoll_fd == -1 {
-					(*data).mutex.signal();
-				}
-				else {
-					let bytes = [0,..8];
-					let content: *mut u64 = cast::transmute(&bytes);
-					*content = 1;
-					let ret = helpers::retry(||
-						libc::write((*data).epoll_fd, bytes.as_ptr() as *libc::c_void, 8) as libc::c_int
-					);
-					if ret == -1 {
-						(*data).mutex.unlock();
-						fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
-					}
-				}
-				(*data).receiver_notified = true;
-
+			if (*data).nr_senders == 0 {
+				notify_receiver(data);
 			}
 			(*data).mutex.unlock();
 		}
@@ -373,4 +938,403 @@ impl<T:Send> Clone for Transmitter<T> {
 		}
 		new
 	}
-}
\ No newline at end of file
+}
+
+/**
+ * An evented counterpart of the Receiver for the sending side of a channel.
+ * Queues a ChannelWritableEvent when a bounded channel has space again after
+ * a `try_send` of this Sender failed with `Full`, and a
+ * ChannelReceiverClosedEvent when the receiver is dropped.
+ */
+pub struct Sender<T> {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv transmitter: Transmitter<T>,
+	priv fd: i32,
+	priv receiver_closed: bool,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl<T> events::EventSource for Sender<T> {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl<T:Send> Sender<T> {
+	pub fn from_transmitter(transmitter: Transmitter<T>, event_queue: &EventQueue) -> ~Sender<T> {
+		let fd = unsafe { syscalls::eventfd(0, 0) };
+		if fd == -1 {
+			fail!("Creating eventfd for sender failed: {}", helpers::last_error().desc);
+		}
+
+		let mut sender = ~Sender{
+			transmitter: transmitter,
+			fd: fd,
+			receiver_closed: false,
+			event_queue: event_queue._get_impl(),
+			process_func: Sender::<T>::process_epoll_events,
+			event_source_info: Rc::new(events::EventSourceInfo::new())
+		};
+
+		let callback: *libc::c_void = unsafe { cast::transmute(&sender.process_func) };
+		sender.event_queue.borrow().with_mut(|q|
+			q.register_fd(fd, syscalls::EPOLLIN, callback)
+		);
+
+		let data = sender.transmitter.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).sender_fds.push(fd);
+			if !(*data).port_alive {
+				sender.receiver_closed = true;
+				sender.event_queue.borrow().with_mut(|q|
+					q.push_back_event(events::Event{
+						event_type: events::ChannelReceiverClosedEvent,
+						is_valid: true,
+						source_info: sender.event_source_info.clone()
+					})
+				);
+			}
+			(*data).mutex.unlock();
+		}
+		sender
+	}
+
+	/// Sends a message. Blocks while a bounded channel is full.
+	pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+		self.transmitter.send(t)
+	}
+
+	pub fn is_closed(&self) -> bool {
+		self.transmitter.is_closed()
+	}
+
+	/**
+	 * Sends a message without blocking. After a `Full` error a
+	 * ChannelWritableEvent will be queued once there is space again.
+	 */
+	pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+		match self.transmitter.try_send(t) {
+			Err(Full(t)) => {
+				self.wait_for_space();
+				Err(Full(t))
+			},
+			ret => ret
+		}
+	}
+
+	/// Requests a notification when the channel has space again
+	fn wait_for_space(&self) {
+		let data = self.transmitter.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			if (*data).queue.len() < (*data).capacity {
+				// A message was received since the send failed
+				signal_sender(data, self.fd);
+			}
+			else if !(*data).blocked_sender_fds.contains(&self.fd) {
+				(*data).blocked_sender_fds.push(self.fd);
+			}
+			(*data).mutex.unlock();
+		}
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let sender: *mut Sender<T> = func_ptr as *mut Sender<T>;
+			let data = (*sender).transmitter.data.get();
+
+			if epoll_events & syscalls::EPOLLIN != 0 {
+				let buffer = [0, ..8];
+
+				let ret = helpers::retry(||
+					libc::read((*sender).fd,
+						       buffer.as_ptr() as *mut libc::c_void,
+						       buffer.len() as libc::size_t) as i32
+				);
+
+				if ret == 8 && !(*sender).receiver_closed {
+					(*data).mutex.lock();
+					let event_type = if !(*data).port_alive {
+						(*sender).receiver_closed = true;
+						Some(events::ChannelReceiverClosedEvent)
+					}
+					else if (*data).queue.len() < (*data).capacity {
+						Some(events::ChannelWritableEvent)
+					}
+					else {
+						// Another sender took the space. Wait for the next one.
+						if !(*data).blocked_sender_fds.contains(&(*sender).fd) {
+							(*data).blocked_sender_fds.push((*sender).fd);
+						}
+						None
+					};
+					(*data).mutex.unlock();
+
+					match event_type {
+						Some(event_type) => {
+							event_queue.push_back_event(events::Event {
+								event_type: event_type,
+								is_valid: true,
+								source_info: (*sender).event_source_info.clone()
+							});
+						},
+						None => {}
+					}
+				}
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for Sender<T> {
+	fn drop(&mut self) {
+		let data = self.transmitter.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let fd = self.fd;
+			(*data).sender_fds.retain(|f| *f != fd);
+			(*data).blocked_sender_fds.retain(|f| *f != fd);
+			(*data).mutex.unlock();
+			libc::close(self.fd);
+		}
+		self.remove_pending_events();
+	}
+}
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use super::{Channel, Receiver, Sender, Select, Data, Empty, Disconnected, Full, Closed, SendError};
+
+	fn pending(queue: &EventQueue) -> uint {
+		queue._get_impl().borrow().with(|q| q.pending_events())
+	}
+
+	#[test]
+	fn messages_arrive_in_order() {
+		let (rx, tx) = Channel::<int>::create_blocking();
+		for i in range(0, 10) {
+			assert!(tx.send(i).is_ok());
+		}
+		for i in range(0, 10) {
+			assert_eq!(rx.recv(), i);
+		}
+		assert_eq!(tx.sent_count(), 10);
+		assert_eq!(rx.received_count(), 10);
+	}
+
+	#[test]
+	fn try_recv_reports_empty_and_disconnected() {
+		let (rx, tx) = Channel::<int>::create_blocking();
+		match rx.try_recv() { Empty => {}, _ => fail!("Expected Empty") }
+		assert!(tx.send(1).is_ok());
+		drop(tx);
+		match rx.try_recv() { Data(1) => {}, _ => fail!("Expected the message") }
+		match rx.try_recv() { Disconnected => {}, _ => fail!("Expected Disconnected") }
+		assert!(rx.recv_opt().is_none());
+	}
+
+	#[test]
+	fn bounded_channel_is_full_at_capacity() {
+		let (rx, tx) = Channel::<int>::create_bounded_blocking(2);
+		assert!(tx.try_send(1).is_ok());
+		assert!(tx.try_send(2).is_ok());
+		match tx.try_send(3) { Err(Full(3)) => {}, _ => fail!("Expected Full") }
+		assert_eq!(rx.recv(), 1);
+		assert!(tx.try_send(3).is_ok());
+		assert_eq!(tx.len(), 2);
+	}
+
+	#[test]
+	fn send_to_closed_channel_returns_message() {
+		let (rx, tx) = Channel::<~str>::create_blocking();
+		drop(rx);
+		assert!(tx.is_closed());
+		match tx.send(~"lost") { Err(SendError(msg)) => assert_eq!(msg, ~"lost"), _ => fail!("Expected an error") }
+		match tx.try_send(~"lost") { Err(Closed(msg)) => assert_eq!(msg, ~"lost"), _ => fail!("Expected Closed") }
+	}
+
+	#[test]
+	fn recv_batch_takes_at_most_max() {
+		let (rx, tx) = Channel::<int>::create_blocking();
+		for i in range(0, 5) {
+			assert!(tx.send(i).is_ok());
+		}
+		let mut buf = ~[];
+		assert_eq!(rx.recv_batch(&mut buf, 3), 3);
+		assert_eq!(buf, ~[0, 1, 2]);
+		assert_eq!(rx.len(), 2);
+	}
+
+	#[test]
+	fn receiver_queues_message_and_closed_events() {
+		let mut queue = EventQueue::new();
+		let (mut rx, tx) = Channel::<int>::create(&queue);
+		assert!(tx.send(1).is_ok());
+		assert!(tx.send(2).is_ok());
+		drop(tx);
+		for _ in range(0, 2) {
+			let event = queue.next_event().unwrap();
+			match event.event_type { events::ChannelMessageEvent => {}, _ => fail!("Expected a ChannelMessageEvent") }
+			assert!(event.originates_from(rx));
+		}
+		match queue.next_event().unwrap().event_type {
+			events::ChannelClosedEvent => {},
+			_ => fail!("Expected a ChannelClosedEvent")
+		}
+		assert_eq!(rx.recv(), Some(1));
+		assert_eq!(rx.recv(), Some(2));
+		assert_eq!(rx.recv(), None);
+	}
+
+	#[test]
+	fn coalesced_receiver_queues_one_event() {
+		let mut queue = EventQueue::new();
+		let (mut rx, tx) = Channel::<int>::create(&queue);
+		rx.set_coalesce_messages(true);
+		for i in range(0, 3) {
+			assert!(tx.send(i).is_ok());
+		}
+		match queue.next_event().unwrap().event_type {
+			events::ChannelMessagesEvent(3) => {},
+			_ => fail!("Expected a ChannelMessagesEvent")
+		}
+		let mut buf = ~[];
+		assert_eq!(rx.recv_batch(&mut buf, 10), 3);
+	}
+
+	#[test]
+	fn sender_gets_writable_event_after_full() {
+		let mut queue = EventQueue::new();
+		let (rx, tx) = Channel::<int>::create_bounded_blocking(1);
+		let sender = Sender::from_transmitter(tx, &queue);
+		assert!(sender.try_send(1).is_ok());
+		assert!(sender.try_send(2).is_err());
+		assert_eq!(rx.recv(), 1);
+		let event = queue.next_event().unwrap();
+		match event.event_type { events::ChannelWritableEvent => {}, _ => fail!("Expected a ChannelWritableEvent") }
+		assert!(event.originates_from(sender));
+		assert_eq!(pending(&queue), 0);
+	}
+
+	#[test]
+	fn recv_timeout_expires_and_can_be_repeated() {
+		let (rx, tx) = Channel::<int>::create_blocking();
+		for _ in range(0, 3) {
+			match rx.recv_timeout(1) { Empty => {}, _ => fail!("Expected Empty") }
+		}
+		assert!(tx.send(5).is_ok());
+		match rx.recv_timeout(1000) { Data(5) => {}, _ => fail!("Expected the message") }
+	}
+
+	#[test]
+	fn select_returns_ready_receiver() {
+		let (rx1, _tx1) = Channel::<int>::create_blocking();
+		let (rx2, tx2) = Channel::<int>::create_blocking();
+		let mut select = Select::new();
+		select.add(&rx1);
+		let index = select.add(&rx2);
+		assert_eq!(select.wait_timeout(1), None);
+		assert!(tx2.send(1).is_ok());
+		assert_eq!(select.wait(), index);
+		assert_eq!(select.wait_timeout(1), Some(index));
+	}
+
+	#[test]
+	fn receiver_of_closed_channel_announces_left_messages() {
+		let mut queue = EventQueue::new();
+		let (rx, tx) = Channel::<int>::create_blocking();
+		assert!(tx.send(1).is_ok());
+		assert!(tx.send(2).is_ok());
+		drop(tx);
+		let mut rx = Receiver::from_blocking_receiver(rx, &queue);
+		assert_eq!(pending(&queue), 3);
+		for _ in range(0, 2) {
+			match queue.next_event().unwrap().event_type {
+				events::ChannelMessageEvent => {},
+				_ => fail!("Expected a ChannelMessageEvent")
+			}
+		}
+		match queue.next_event().unwrap().event_type {
+			events::ChannelClosedEvent => {},
+			_ => fail!("Expected a ChannelClosedEvent")
+		}
+		assert_eq!(rx.recv(), Some(1));
+		assert_eq!(rx.recv(), Some(2));
+	}
+
+	#[test]
+	fn rebind_keeps_coalescing() {
+		let queue = EventQueue::new();
+		let other = EventQueue::new();
+		let (mut rx, tx) = Channel::<int>::create(&queue);
+		rx.set_coalesce_messages(true);
+		rx.set_water_marks(10, 2);
+		assert!(tx.send(1).is_ok());
+		assert!(tx.send(2).is_ok());
+		drop(tx);
+		let mut rx = rx.rebind(&other);
+		assert_eq!(rx.get_water_marks(), (10, 2));
+		assert_eq!(pending(&queue), 0);
+		assert_eq!(pending(&other), 2);
+		let mut buf = ~[];
+		assert_eq!(rx.recv_batch(&mut buf, 10), 2);
+	}
+
+	#[test]
+	fn metrics_count_messages_and_senders() {
+		let (rx, tx) = Channel::<int>::create_blocking();
+		let tx2 = tx.clone();
+		assert_eq!(rx.sender_count(), 2);
+		assert!(tx.send(1).is_ok());
+		assert!(tx2.send(2).is_ok());
+		assert_eq!(tx.len(), 2);
+		rx.recv();
+		assert_eq!(tx2.sent_count(), 2);
+		assert_eq!(tx2.received_count(), 1);
+		drop(tx2);
+		assert_eq!(rx.sender_count(), 1);
+		assert!(tx.is_receiver_alive());
+		drop(rx);
+		assert!(!tx.is_receiver_alive());
+	}
+
+	#[test]
+	fn water_marks_queue_high_and_low_events() {
+		let mut queue = EventQueue::new();
+		let (mut rx, tx) = Channel::<int>::create(&queue);
+		assert!(tx.send(1).is_ok());
+		assert!(tx.send(2).is_ok());
+		rx.set_water_marks(2, 0);
+		match queue.next_event().unwrap().event_type {
+			events::ChannelHighWaterEvent(2) => {},
+			_ => fail!("Expected a ChannelHighWaterEvent")
+		}
+		for _ in range(0, 2) {
+			queue.next_event().unwrap();
+			rx.recv();
+		}
+		match queue.next_event().unwrap().event_type {
+			events::ChannelLowWaterEvent(0) => {},
+			_ => fail!("Expected a ChannelLowWaterEvent")
+		}
+	}
+
+	#[test]
+	#[should_fail]
+	fn high_water_above_capacity_fails() {
+		let queue = EventQueue::new();
+		let (mut rx, _tx) = Channel::<int>::create_bounded(2, &queue);
+		rx.set_water_marks(3, 1);
+	}
+}
@@ -10,6 +10,9 @@
 
 use std::cast;
 use std::libc;
+use std::util;
+use std::unstable::mutex::Mutex;
+use std::sync::arc::UnsafeArc;
 use collections::ringbuf::RingBuf;
 use collections::deque::Deque;
 
@@ -19,9 +22,45 @@ use super::IoResult;
 use super::syscalls;
 use super::helpers;
 
+// Epoll user data for the timerfd that is shared by coalesced timers
+static SLACK_TIMER_TOKEN: uint = 1;
+// Epoll user data for the eventfd of the RemoteWaker
+static REMOTE_WAKEUP_TOKEN: uint = 2;
+
 pub struct EventQueueImpl {
 	priv fd: i32, // epoll fd,
-	priv ready_events: RingBuf<events::Event>
+	priv ready_events: RingBuf<events::Event>,
+	priv slack_timer_fd: i32,
+	priv slack_timers: ~[SlackTimerEntry],
+	priv remote_waker: Option<RemoteWaker>,
+	priv owned_fds: ~[~OwnedFd]
+}
+
+/**
+ * State that the EventQueue keeps alive together with an fd, e.g. the
+ * remaining work of a dropped event source.
+ */
+pub trait FdOwner {
+	/**
+	 * Called like an epoll callback when the fd is ready.
+	 * Returns false when the owner is done. Then the fd is unregistered
+	 * and the owner is dropped, which should close the fd.
+	 */
+	fn process(&mut self, event_queue: &mut EventQueueImpl, epoll_events: u32) -> bool;
+}
+
+struct OwnedFd {
+	process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	fd: i32,
+	owner: ~FdOwner:
+}
+
+/// A timer that may expire anywhere within [deadline, deadline + slack]
+struct SlackTimerEntry {
+	callback: *libc::c_void,
+	deadline: u64,
+	slack: u64,
+	interval: u64 // 0 for singleshot timers
 }
 
 impl IEventQueue for EventQueueImpl {
@@ -44,7 +83,11 @@ impl EventQueueImpl {
 		}
 		EventQueueImpl{
 				fd: fd,
-				ready_events: RingBuf::new()
+				ready_events: RingBuf::new(),
+				slack_timer_fd: -1,
+				slack_timers: ~[],
+				remote_waker: None,
+				owned_fds: ~[]
 		}
 	}
 
@@ -81,13 +124,207 @@ impl EventQueueImpl {
 		}
 		else if ready_fds == 1 {
 			let ptr = evs.data.get_data_as_ptr();
-			let cb: *fn(*libc::c_void, &mut EventQueueImpl, u32) 
+			if ptr as uint == SLACK_TIMER_TOKEN {
+				self.process_slack_timers();
+			}
+			else if ptr as uint == REMOTE_WAKEUP_TOKEN {
+				self.process_remote_wakeups();
+			}
+			else {
+				let cb: *fn(*libc::c_void, &mut EventQueueImpl, u32) 
+				        = unsafe { cast::transmute(ptr) };
+				unsafe { (*cb)(ptr, self, evs.events) };
+			}
+		}
+		Ok(())
+	}
+
+	/**
+	 * Returns a handle that allows other threads to invoke callbacks on this queue.
+	 * All handles share a single eventfd which is created on first use.
+	 */
+	pub fn remote_waker(&mut self) -> RemoteWaker {
+		if self.remote_waker.is_none() {
+			let fd = unsafe { syscalls::eventfd(0, 0) };
+			if fd == -1 {
+				fail!("Creating eventfd failed: {}", helpers::last_error().desc);
+			}
+			self.register_fd(fd, syscalls::EPOLLIN, REMOTE_WAKEUP_TOKEN as *libc::c_void);
+			self.remote_waker = Some(RemoteWaker {
+				data: UnsafeArc::new(RemoteWakerData {
+					mutex: unsafe { Mutex::new() },
+					fd: fd,
+					alive: true,
+					pending: ~[]
+				})
+			});
+		}
+		self.remote_waker.get_ref().clone()
+	}
+
+	/// Invokes the callbacks that were passed to RemoteWaker::wake
+	fn process_remote_wakeups(&mut self) {
+		let pending = {
+			let data = self.remote_waker.get_ref().data.get();
+			unsafe {
+				let buffer = [0u8, ..8];
+				helpers::retry(||
+					libc::read((*data).fd,
+					           buffer.as_ptr() as *mut libc::c_void,
+					           buffer.len() as libc::size_t) as i32
+				);
+				(*data).mutex.lock();
+				let pending = util::replace(&mut (*data).pending, ~[]);
+				(*data).mutex.unlock();
+				pending
+			}
+		};
+
+		for callback in pending.iter() {
+			let ptr = *callback as *libc::c_void;
+			let cb: *fn(*libc::c_void, &mut EventQueueImpl, u32)
 			        = unsafe { cast::transmute(ptr) };
-			unsafe { (*cb)(ptr, self, evs.events) };
+			unsafe { (*cb)(ptr, self, syscalls::EPOLLIN) };
 		}
+	}
+
+	/**
+	 * Adds a timer that shares its wakeups with other timers whose windows overlap.
+	 * The callback is invoked like an epoll callback with EPOLLIN once per
+	 * expiration, also for expirations of a periodic timer that were missed.
+	 * Interval and slack are in milliseconds.
+	 */
+	pub fn add_slack_timer(&mut self, callback: *libc::c_void, interval: u32, slack: u32, singleshot: bool) {
+		if self.slack_timer_fd == -1 {
+			let tfd = unsafe {
+				syscalls::timerfd_create(libc::CLOCK_MONOTONIC, 0)
+			};
+			if tfd == -1 {
+				fail!("Could not create timerfd: {}", helpers::last_error().desc);
+			}
+			self.slack_timer_fd = tfd;
+			self.register_fd(tfd, syscalls::EPOLLIN, SLACK_TIMER_TOKEN as *libc::c_void);
+		}
+
+		let interval = interval as u64 * 1000000;
+		self.slack_timers.push(SlackTimerEntry {
+			callback: callback,
+			deadline: helpers::monotonic_time_ns() + interval,
+			slack: slack as u64 * 1000000,
+			interval: if singleshot { 0 } else { interval }
+		});
+		self.arm_slack_timer();
+	}
+
+	pub fn remove_slack_timer(&mut self, callback: *libc::c_void) {
+		self.slack_timers.retain(|entry| entry.callback != callback);
+		self.arm_slack_timer();
+	}
+
+	/// Arms the shared timerfd for the earliest end of all slack windows
+	fn arm_slack_timer(&mut self) {
+		if self.slack_timer_fd == -1 { return; }
+
+		let mut new_value = syscalls::itimerspec::new(); // 0 disarms the timer
+		match self.next_slack_wakeup() {
+			Some(w) => {
+				new_value.it_value.tv_sec = (w / 1000000000) as libc::time_t;
+				new_value.it_value.tv_nsec = (w % 1000000000) as libc::c_long;
+			},
+			None => {}
+		}
+		let ret = unsafe {
+			syscalls::timerfd_settime(self.slack_timer_fd, syscalls::TFD_TIMER_ABSTIME,
+			                          &new_value, 0 as *syscalls::itimerspec)
+		};
+		if ret != 0 {
+			fail!("Error on arming timer {0}", helpers::last_error().desc);
+		}
+	}
+
+	/// The earliest end of all slack windows
+	fn next_slack_wakeup(&self) -> Option<u64> {
+		let mut wakeup: Option<u64> = None;
+		for entry in self.slack_timers.iter() {
+			let latest = entry.deadline + entry.slack;
+			wakeup = match wakeup {
+				Some(w) if w <= latest => Some(w),
+				_ => Some(latest)
+			};
+		}
+		wakeup
+	}
+
+	fn process_slack_timers(&mut self) {
+		let buffer = [0u8, ..8];
+		helpers::retry(|| unsafe {
+			libc::read(self.slack_timer_fd,
+			           buffer.as_ptr() as *mut libc::c_void,
+			           buffer.len() as libc::size_t) as i32
+		});
+		self.expire_slack_timers(helpers::monotonic_time_ns());
+	}
+
+	/// Expires all timers whose slack window has started at `now`
+	fn expire_slack_timers(&mut self, now: u64) {
+		// Callback and number of expirations since the last wakeup
+		let mut expired: ~[(*libc::c_void, uint)] = ~[];
+		for entry in self.slack_timers.mut_iter() {
+			if entry.deadline <= now {
+				let mut expirations = 1;
+				if entry.interval != 0 {
+					entry.deadline += entry.interval;
+					while entry.deadline <= now {
+						entry.deadline += entry.interval;
+						expirations += 1;
+					}
+				}
+				expired.push((entry.callback, expirations));
+			}
+		}
+		self.slack_timers.retain(|entry| entry.interval != 0 || entry.deadline > now);
+		self.arm_slack_timer();
+
+		// Like a timerfd, each expiration is reported
+		for &(ptr, expirations) in expired.iter() {
+			let cb: *fn(*libc::c_void, &mut EventQueueImpl, u32)
+			        = unsafe { cast::transmute(ptr) };
+			for _ in range(0, expirations) {
+				unsafe { (*cb)(ptr, self, syscalls::EPOLLIN) };
+			}
+		}
+	}
+
+	/**
+	 * Hands the owner over to the queue together with `fd`, which is
+	 * registered for `flags`. The owner is processed until it is done and
+	 * dropped with the EventQueue otherwise. If the fd can't be registered
+	 * the owner is dropped right away.
+	 */
+	pub fn add_owned_fd(&mut self, fd: i32, flags: u32, owner: ~FdOwner:) -> IoResult<()> {
+		let entry = ~OwnedFd {
+			process_func: EventQueueImpl::process_owned_fd,
+			fd: fd,
+			owner: owner
+		};
+		let callback: *libc::c_void = unsafe { cast::transmute(&entry.process_func) };
+		if_ok!(self.try_register_fd(fd, flags, callback));
+		self.owned_fds.push(entry);
 		Ok(())
 	}
 
+	fn process_owned_fd(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		let entry = func_ptr as *mut OwnedFd;
+		let keep = unsafe { (*entry).owner.process(event_queue, epoll_events) };
+		if !keep {
+			event_queue.unregister_fd(unsafe { (*entry).fd });
+			event_queue.owned_fds.retain(|owned| {
+				let ptr: *OwnedFd = &**owned;
+				ptr != entry as *OwnedFd
+			});
+		}
+	}
+
 	pub fn remove_pending_events(&mut self, condition: |event: &events::Event|-> bool) {//event_source: &event::EventSource) {
 		for ev in self.ready_events.mut_iter() {
 			if condition(ev) {
@@ -96,7 +333,25 @@ impl EventQueueImpl {
 		}
 	}
 
+	/// Returns the number of queued events that were not removed
+	pub fn pending_events(&self) -> uint {
+		let mut count = 0;
+		for ev in self.ready_events.iter() {
+			if ev.is_valid {
+				count += 1;
+			}
+		}
+		count
+	}
+
 	pub fn register_fd(&mut self, fd: i32, flags: u32, callback: *libc::c_void) {
+		if self.try_register_fd(fd, flags, callback).is_err() {
+			fail!("Could not register fd for epoll");
+		}
+	}
+
+	/// Like register_fd, but returns the error, e.g. for regular files which epoll doesn't support
+	pub fn try_register_fd(&mut self, fd: i32, flags: u32, callback: *libc::c_void) -> IoResult<()> {
 		// Initialize epoll data
 		let mut data = syscalls::epoll_data::new();
 		data.set_data_as_ptr(callback);		
@@ -109,8 +364,9 @@ impl EventQueueImpl {
 			syscalls::epoll_ctl(self.fd, syscalls::EPOLL_CTL_ADD, fd, &event) 
 		};
 		if s != 0 {
-			fail!("Could not register fd for epoll");
+			return Err(helpers::last_error());
 		}
+		Ok(())
 	}
 
 	pub fn modify_fd(&mut self, fd: i32, flags: u32, callback: *libc::c_void) {
@@ -148,6 +404,165 @@ impl EventQueueImpl {
 #[unsafe_destructor]
 impl Drop for EventQueueImpl {
 	fn drop(&mut self) {
+		match self.remote_waker {
+			Some(ref waker) => {
+				let data = waker.data.get();
+				unsafe {
+					(*data).mutex.lock();
+					(*data).alive = false;
+					(*data).pending.clear();
+					libc::close((*data).fd);
+					(*data).mutex.unlock();
+				}
+			},
+			None => {}
+		}
+		if self.slack_timer_fd != -1 {
+			unsafe { libc::close(self.slack_timer_fd); }
+		}
+		// The owners close their fds
+		self.owned_fds.clear();
 		unsafe { libc::close(self.fd); }
 	}
-}
\ No newline at end of file
+}
+
+/**
+ * A handle that can be sent to other threads to invoke an epoll style
+ * callback on the thread of an EventQueue without a dedicated fd.
+ * Callbacks are identified by the address of their process function.
+ */
+pub struct RemoteWaker {
+	priv data: UnsafeArc<RemoteWakerData>
+}
+
+struct RemoteWakerData {
+	mutex: Mutex,
+	fd: i32,
+	alive: bool,
+	pending: ~[uint]
+}
+
+impl RemoteWaker {
+	/**
+	 * Schedules the callback to be invoked by the event queue.
+	 * Returns false if the event queue is gone.
+	 */
+	pub fn wake(&self, callback: *libc::c_void) -> bool {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			if !(*data).alive {
+				(*data).mutex.unlock();
+				return false;
+			}
+			if (*data).pending.len() == 0 {
+				if helpers::signal_eventfd((*data).fd) == -1 {
+					(*data).mutex.unlock();
+					fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
+				}
+			}
+			(*data).pending.push(callback as uint);
+			(*data).mutex.unlock();
+		}
+		true
+	}
+
+	/// Removes a scheduled callback. Must be called before the callback target is dropped.
+	pub fn cancel(&self, callback: *libc::c_void) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).pending.retain(|cb| *cb != callback as uint);
+			(*data).mutex.unlock();
+		}
+	}
+}
+
+impl Clone for RemoteWaker {
+	fn clone(&self) -> RemoteWaker {
+		RemoteWaker {
+			data: self.data.clone()
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use timer::Timer;
+
+	static MS: u64 = 1000000;
+
+	#[test]
+	fn slack_timer_reports_each_missed_expiration() {
+		let mut queue = EventQueue::new();
+		let mut timer = Timer::create(&queue).unwrap();
+		timer.set_interval(10);
+		timer.set_slack(5);
+		timer.start();
+		queue._get_impl().borrow().with_mut(|q| {
+			let deadline = q.slack_timers[0].deadline;
+			q.expire_slack_timers(deadline - 1);
+			assert_eq!(q.pending_events(), 0);
+			// A wakeup that is 95ms late covers 10 expirations
+			q.expire_slack_timers(deadline + 95 * MS);
+			assert_eq!(q.pending_events(), 10);
+			assert_eq!(q.slack_timers[0].deadline, deadline + 100 * MS);
+		});
+		for _ in range(0, 10) {
+			let event = queue.next_event().unwrap();
+			match event.event_type {
+				events::TimerEvent => {},
+				_ => fail!("Expected a TimerEvent")
+			}
+			assert!(event.originates_from(timer));
+		}
+		assert!(timer.is_active());
+	}
+
+	#[test]
+	fn timers_with_overlapping_windows_share_a_wakeup() {
+		let queue = EventQueue::new();
+		let mut timers = ~[];
+		for &slack in [5u32, 5, 1].iter() {
+			let mut timer = Timer::create(&queue).unwrap();
+			timer.set_interval(10);
+			timer.set_slack(slack);
+			timer.start();
+			timers.push(timer);
+		}
+		queue._get_impl().borrow().with_mut(|q| {
+			let deadline = q.slack_timers[0].deadline;
+			q.slack_timers[1].deadline = deadline + 3 * MS;
+			q.slack_timers[2].deadline = deadline + 7 * MS;
+			// The first window ends first and the second one has started by then
+			let wakeup = q.next_slack_wakeup().unwrap();
+			assert_eq!(wakeup, deadline + 5 * MS);
+			q.expire_slack_timers(wakeup);
+			assert_eq!(q.pending_events(), 2);
+			assert_eq!(q.slack_timers[0].deadline, deadline + 10 * MS);
+			assert_eq!(q.slack_timers[1].deadline, deadline + 13 * MS);
+			// The third window hadn't started and now ends first
+			assert_eq!(q.next_slack_wakeup(), Some(deadline + 8 * MS));
+		});
+	}
+
+	#[test]
+	fn singleshot_slack_timer_is_removed_after_expiring() {
+		let queue = EventQueue::new();
+		let mut timer = Timer::create(&queue).unwrap();
+		timer.set_interval(10);
+		timer.set_slack(5);
+		timer.set_singleshot(true);
+		timer.start();
+		queue._get_impl().borrow().with_mut(|q| {
+			let deadline = q.slack_timers[0].deadline;
+			q.expire_slack_timers(deadline + 50 * MS);
+			assert_eq!(q.pending_events(), 1);
+			assert_eq!(q.slack_timers.len(), 0);
+			assert_eq!(q.next_slack_wakeup(), None);
+		});
+		assert!(!timer.is_active());
+	}
+}
@@ -8,10 +8,15 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cast;
 use std::libc;
 use std::io;
 use std::os;
 use std::io::IoError;
+use std::local_data;
+
+use super::IoResult;
+use super::syscalls;
 
 #[cfg(unix)]
 #[inline]
@@ -62,4 +67,97 @@ pub fn translate_error(errno: i32, detail: bool) -> IoError {
 	}
 }
 
-pub fn last_error() -> IoError { translate_error(os::errno() as i32, true) }
\ No newline at end of file
+pub fn last_error() -> IoError { translate_error(os::errno() as i32, true) }
+
+/// Returns the current CLOCK_MONOTONIC time in nanoseconds
+pub fn monotonic_time_ns() -> u64 {
+	let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+	unsafe { syscalls::clock_gettime(libc::CLOCK_MONOTONIC, &mut now); }
+	now.tv_sec as u64 * 1000000000 + now.tv_nsec as u64
+}
+
+/// Adds 1 to the counter of an eventfd
+pub fn signal_eventfd(fd: i32) -> libc::c_int {
+	let bytes = [0u8,..8];
+	unsafe {
+		let content: *mut u64 = cast::transmute(&bytes);
+		*content = 1;
+		retry(||
+			libc::write(fd, bytes.as_ptr() as *libc::c_void, 8) as libc::c_int
+		)
+	}
+}
+
+/// Writes as many bytes as possible to a non-blocking fd. Returns 0 if the fd would block.
+pub fn write_fd(fd: i32, buf: &[u8]) -> IoResult<uint> {
+	let ret = retry(|| unsafe {
+		libc::write(fd, buf.as_ptr() as *libc::c_void, buf.len() as libc::size_t) as libc::c_int
+	});
+	if ret < 0 {
+		let errno = os::errno() as int;
+		if errno == libc::EWOULDBLOCK as int || errno == libc::EAGAIN as int {
+			return Ok(0);
+		}
+		return Err(last_error());
+	}
+	Ok(ret as uint)
+}
+
+/// Watchers of the signals on one thread, because the signal mask belongs to the thread
+struct SignalWatchers {
+	// Number of live watchers of each signal. Linux signals go up to 64.
+	counts: [uint, ..65],
+	// Whether the signal was unblocked before its first watcher blocked it
+	unblock: [bool, ..65]
+}
+
+local_data_key!(SIGNAL_WATCHERS: SignalWatchers)
+
+/**
+ * Blocks the signals for the calling thread and registers a watcher for
+ * each of them. The signals must be valid and contained in `mask`.
+ */
+pub fn watch_signals(mask: &syscalls::sigset_t, signals: &[int]) -> IoResult<()> {
+	let mut old_mask = syscalls::sigset_t::new();
+	// pthread_sigmask returns the error instead of setting errno
+	let ret = unsafe { syscalls::pthread_sigmask(syscalls::SIG_BLOCK, mask, &mut old_mask) };
+	if ret != 0 {
+		return Err(translate_error(ret, true));
+	}
+	let mut watchers = local_data::pop(SIGNAL_WATCHERS).unwrap_or(
+		SignalWatchers { counts: [0, ..65], unblock: [false, ..65] });
+	for signo in signals.iter() {
+		let signo = *signo as uint;
+		if watchers.counts[signo] == 0 {
+			watchers.unblock[signo] = unsafe { syscalls::sigismember(&old_mask, signo as i32) } == 0;
+		}
+		watchers.counts[signo] += 1;
+	}
+	local_data::set(SIGNAL_WATCHERS, watchers);
+	Ok(())
+}
+
+/**
+ * Removes the watchers that `watch_signals` registered. A signal is only
+ * unblocked when the last watcher on the thread is gone and it wasn't
+ * blocked before the first one. Must be called on the thread that called
+ * `watch_signals`, which is the case for the event sources of an EventQueue.
+ */
+pub fn unwatch_signals(signals: &[int]) {
+	let mut watchers = match local_data::pop(SIGNAL_WATCHERS) {
+		Some(watchers) => watchers,
+		None => return
+	};
+	let mut mask = syscalls::sigset_t::new();
+	let mut old_mask = syscalls::sigset_t::new();
+	for signo in signals.iter() {
+		let signo = *signo as uint;
+		watchers.counts[signo] -= 1;
+		if watchers.counts[signo] == 0 && watchers.unblock[signo] {
+			unsafe { syscalls::sigaddset(&mut mask, signo as i32); }
+		}
+	}
+	local_data::set(SIGNAL_WATCHERS, watchers);
+	// Signals that are still pending are delivered with their default action
+	unsafe { syscalls::pthread_sigmask(syscalls::SIG_UNBLOCK, &mask, &mut old_mask); }
+}
@@ -0,0 +1,374 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::io;
+use std::libc;
+use std::os;
+use std::vec;
+use std::io::IoError;
+use std::cell::RefCell;
+use std::rc::Rc;
+use collections::ringbuf::RingBuf;
+use collections::deque::Deque;
+
+use super::IoResult;
+use super::events;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::channel::SendError;
+use super::syscalls;
+use super::helpers;
+
+/// Default for the biggest message an IpcReceiver accepts
+pub static DEFAULT_MAX_MESSAGE_SIZE: uint = 65536;
+
+// Each packet starts with this byte so that an empty message can't be
+// confused with the end of the stream
+static MESSAGE_MARKER: u8 = 1;
+
+// Messages that are read per wakeup, so that a busy sender can't starve
+// the other event sources and the queued messages stay bounded
+static MAX_MESSAGES_PER_WAKEUP: uint = 64;
+
+/// Converts messages from and to the bytes that are sent between the processes
+pub trait MessageCodec<T> {
+	/// Appends the serialized message to `buf`
+	fn encode(&self, msg: &T, buf: &mut ~[u8]);
+	fn decode(&self, buf: &[u8]) -> IoResult<T>;
+}
+
+/// Sends byte vectors unchanged
+pub struct BytesCodec;
+
+impl MessageCodec<~[u8]> for BytesCodec {
+	fn encode(&self, msg: &~[u8], buf: &mut ~[u8]) {
+		buf.push_all(msg.as_slice());
+	}
+
+	fn decode(&self, buf: &[u8]) -> IoResult<~[u8]> {
+		Ok(buf.to_owned())
+	}
+}
+
+/// One end of an IPC channel. Closes the socket when dropped.
+pub struct IpcEndpoint {
+	priv fd: i32
+}
+
+impl IpcEndpoint {
+	/// Takes ownership of a socket that was inherited from the parent process
+	pub unsafe fn from_fd(fd: i32) -> IpcEndpoint {
+		IpcEndpoint{fd: fd}
+	}
+
+	pub fn fd(&self) -> i32 {
+		self.fd
+	}
+
+	/// Releases the socket without closing it
+	pub fn into_fd(self) -> i32 {
+		let mut endpoint = self;
+		let fd = endpoint.fd;
+		endpoint.fd = -1;
+		fd
+	}
+}
+
+impl Drop for IpcEndpoint {
+	fn drop(&mut self) {
+		if self.fd != -1 {
+			unsafe { libc::close(self.fd); }
+			self.fd = -1;
+		}
+	}
+}
+
+pub struct IpcChannel;
+
+impl IpcChannel {
+	/**
+	 * Creates a connected pair of endpoints, the first one for the
+	 * IpcReceiver and the second one for the IpcTransmitter.
+	 * After a fork each process keeps one of them and drops the other,
+	 * otherwise the death of the peer can't be detected.
+	 * The sockets are close-on-exec. A process that execs a worker must
+	 * pass the descriptor explicitly, e.g. by dup2'ing it.
+	 */
+	pub fn create_pair() -> IoResult<(IpcEndpoint, IpcEndpoint)> {
+		let mut fds = [-1i32, -1i32];
+		let ret = unsafe {
+			syscalls::socketpair(syscalls::AF_UNIX,
+			                     syscalls::SOCK_SEQPACKET | syscalls::SOCK_CLOEXEC,
+			                     0, fds.as_mut_ptr())
+		};
+		if ret == -1 {
+			return Err(helpers::last_error());
+		}
+		Ok((IpcEndpoint{fd: fds[0]}, IpcEndpoint{fd: fds[1]}))
+	}
+}
+
+/**
+ * The sending side of an IPC channel. Sending blocks while the socket
+ * buffer is full, like sending on a bounded Channel.
+ */
+pub struct IpcTransmitter<T, C> {
+	priv endpoint: IpcEndpoint,
+	priv codec: C,
+	priv buffer: ~[u8],
+	priv closed: bool
+}
+
+impl<T, C: MessageCodec<T>> IpcTransmitter<T, C> {
+	pub fn from_endpoint(endpoint: IpcEndpoint, codec: C) -> IpcTransmitter<T, C> {
+		syscalls::set_fd_blocking(endpoint.fd, true);
+		IpcTransmitter{
+			endpoint: endpoint,
+			codec: codec,
+			buffer: ~[],
+			closed: false
+		}
+	}
+
+	/**
+	 * Sends a message. Hands the message back if the receiving process
+	 * is gone or the message is bigger than the socket buffer.
+	 */
+	pub fn send(&mut self, t: T) -> Result<(), SendError<T>> {
+		if self.closed {
+			return Err(SendError(t));
+		}
+		self.buffer.clear();
+		self.buffer.push(MESSAGE_MARKER);
+		self.codec.encode(&t, &mut self.buffer);
+
+		let fd = self.endpoint.fd;
+		let data = self.buffer.as_ptr();
+		let len = self.buffer.len();
+		let ret = helpers::retry(|| {
+			unsafe {
+				libc::send(fd,
+					data as *mut libc::c_void,
+					len as libc::size_t,
+					syscalls::MSG_NOSIGNAL) as libc::c_int
+			}
+		});
+		if ret < 0 {
+			let errno = os::errno() as int;
+			if errno == libc::EPIPE as int || errno == libc::ECONNRESET as int {
+				self.closed = true;
+			}
+			return Err(SendError(t));
+		}
+		Ok(())
+	}
+
+	/// Returns true if a send has detected that the receiving process is gone
+	pub fn is_closed(&self) -> bool {
+		self.closed
+	}
+}
+
+/**
+ * The receiving side of an IPC channel.
+ * Queues a ChannelMessageEvent for each message and a ChannelClosedEvent
+ * when the transmitting process closed its end or died.
+ * Messages that can't be decoded produce an IoErrorEvent.
+ */
+pub struct IpcReceiver<T, C> {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv endpoint: IpcEndpoint,
+	priv codec: C,
+	priv messages: RingBuf<T>,
+	priv buffer: ~[u8],
+	priv closed: bool,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl<T, C> events::EventSource for IpcReceiver<T, C> {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl<T, C: MessageCodec<T>> IpcReceiver<T, C> {
+	pub fn from_endpoint(endpoint: IpcEndpoint, codec: C, event_queue: &EventQueue) -> ~IpcReceiver<T, C> {
+		syscalls::set_fd_blocking(endpoint.fd, false);
+		let receiver = ~IpcReceiver{
+			process_func: IpcReceiver::<T, C>::process_epoll_events,
+			endpoint: endpoint,
+			codec: codec,
+			messages: RingBuf::new(),
+			buffer: vec::from_elem(DEFAULT_MAX_MESSAGE_SIZE + 1, 0u8),
+			closed: false,
+			event_queue: event_queue._get_impl(),
+			event_source_info: Rc::new(events::EventSourceInfo::new())
+		};
+		let callback: *libc::c_void = unsafe { cast::transmute(&receiver.process_func) };
+		receiver.event_queue.borrow().with_mut(|q|
+			q.register_fd(receiver.endpoint.fd, syscalls::EPOLLIN, callback)
+		);
+		receiver
+	}
+
+	/// Sets the size of the biggest message that can be received
+	pub fn set_max_message_size(&mut self, size: uint) {
+		self.buffer = vec::from_elem(size + 1, 0u8);
+	}
+
+	/// Fetches the message that belongs to a ChannelMessageEvent
+	pub fn recv(&mut self) -> Option<T> {
+		self.messages.pop_front()
+	}
+
+	fn push_event(&mut self, event_queue: &mut EventQueueImpl, event_type: events::EventKind) {
+		event_queue.push_back_event(events::Event{
+			event_type: event_type,
+			is_valid: true,
+			source_info: self.event_source_info.clone()
+		});
+	}
+
+	fn close(&mut self, event_queue: &mut EventQueueImpl) {
+		self.closed = true;
+		event_queue.unregister_fd(self.endpoint.fd);
+		self.push_event(event_queue, events::ChannelClosedEvent);
+	}
+
+	/**
+	 * Reads up to MAX_MESSAGES_PER_WAKEUP messages. The socket is registered
+	 * level triggered, so epoll reports it again for the remaining messages
+	 * once the queued events were handled.
+	 */
+	fn read_messages(&mut self, event_queue: &mut EventQueueImpl) {
+		let fd = self.endpoint.fd;
+		for _ in range(0, MAX_MESSAGES_PER_WAKEUP) {
+			let buf = self.buffer.as_mut_ptr();
+			let len = self.buffer.len();
+			let ret = helpers::retry(|| {
+				unsafe {
+					libc::recv(fd,
+						buf as *mut libc::c_void,
+						len as libc::size_t,
+						syscalls::MSG_TRUNC) as libc::c_int
+				}
+			});
+
+			if ret == 0 {
+				self.close(event_queue);
+				return;
+			}
+			else if ret < 0 {
+				let errno = os::errno() as int;
+				if errno == libc::EWOULDBLOCK as int || errno == libc::EAGAIN as int {
+					return;
+				}
+				let err = helpers::last_error();
+				self.push_event(event_queue, events::IoErrorEvent(err));
+				self.close(event_queue);
+				return;
+			}
+
+			let size = ret as uint;
+			let decoded = if size > len {
+				// With MSG_TRUNC recv returns the real size of the packet
+				Err(IoError{
+					kind: io::InvalidInput,
+					desc: "Message exceeds the maximum message size",
+					detail: Some(format!("{} bytes", size - 1))
+				})
+			}
+			else if self.buffer[0] != MESSAGE_MARKER {
+				Err(IoError{
+					kind: io::InvalidInput,
+					desc: "Invalid message",
+					detail: None
+				})
+			}
+			else {
+				self.codec.decode(self.buffer.slice(1, size))
+			};
+
+			match decoded {
+				Ok(msg) => {
+					self.messages.push_back(msg);
+					self.push_event(event_queue, events::ChannelMessageEvent);
+				},
+				Err(err) => self.push_event(event_queue, events::IoErrorEvent(err))
+			}
+		}
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let receiver: *mut IpcReceiver<T, C> = func_ptr as *mut IpcReceiver<T, C>;
+			if (*receiver).closed { return; }
+
+			// On a hangup the remaining messages are read before the end of the stream
+			if epoll_events & (syscalls::EPOLLIN | syscalls::EPOLLHUP | syscalls::EPOLLERR) != 0 {
+				(*receiver).read_messages(event_queue);
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl<T, C: MessageCodec<T>> Drop for IpcReceiver<T, C> {
+	fn drop(&mut self) {
+		if !self.closed {
+			let fd = self.endpoint.fd;
+			self.event_queue.borrow().with_mut(|q| q.unregister_fd(fd));
+		}
+		self.remove_pending_events();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use super::{IpcChannel, IpcTransmitter, IpcReceiver, BytesCodec, MAX_MESSAGES_PER_WAKEUP};
+
+	fn pending(queue: &EventQueue) -> uint {
+		queue._get_impl().borrow().with(|q| q.pending_events())
+	}
+
+	fn poll(queue: &EventQueue) {
+		queue._get_impl().borrow().with_mut(|q| q.poll_events()).unwrap();
+	}
+
+	#[test]
+	fn messages_are_read_in_batches() {
+		let queue = EventQueue::new();
+		let (rx_end, tx_end) = IpcChannel::create_pair().unwrap();
+		let mut tx = IpcTransmitter::from_endpoint(tx_end, BytesCodec);
+		let mut rx = IpcReceiver::from_endpoint(rx_end, BytesCodec, &queue);
+		let count = MAX_MESSAGES_PER_WAKEUP + 10;
+		for i in range(0, count) {
+			assert!(tx.send(~[i as u8]).is_ok());
+		}
+		poll(&queue);
+		assert_eq!(pending(&queue), MAX_MESSAGES_PER_WAKEUP);
+		poll(&queue);
+		assert_eq!(pending(&queue), count);
+		for i in range(0, count) {
+			assert_eq!(rx.recv(), Some(~[i as u8]));
+		}
+	}
+}
@@ -0,0 +1,218 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use collections::ringbuf::RingBuf;
+use collections::deque::Deque;
+
+use super::events;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::channel::SendError;
+
+struct LocalChannelData<T> {
+	queue: RingBuf<T>,
+	nr_transmitters: uint,
+	receiver_alive: bool
+}
+
+/**
+ * A channel between components that run on the same EventQueue.
+ * Sending pushes a ChannelMessageEvent directly into the queue, so no
+ * mutex, eventfd or syscall is involved. Neither end can be sent to
+ * another task.
+ */
+pub struct LocalChannel<T>;
+
+impl<T> LocalChannel<T> {
+	pub fn create(event_queue: &EventQueue) -> (LocalReceiver<T>, LocalTransmitter<T>) {
+		let data = Rc::new(RefCell::new(LocalChannelData{
+			queue: RingBuf::new(),
+			nr_transmitters: 1,
+			receiver_alive: true
+		}));
+		let event_source_info = Rc::new(events::EventSourceInfo::new());
+		let receiver = LocalReceiver{
+			data: data.clone(),
+			event_queue: event_queue._get_impl(),
+			event_source_info: event_source_info.clone()
+		};
+		let transmitter = LocalTransmitter{
+			data: data,
+			event_queue: event_queue._get_impl(),
+			event_source_info: event_source_info
+		};
+		(receiver, transmitter)
+	}
+}
+
+pub struct LocalReceiver<T> {
+	priv data: Rc<RefCell<LocalChannelData<T>>>,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl<T> events::EventSource for LocalReceiver<T> {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl<T> LocalReceiver<T> {
+	/// Fetches the message that belongs to a ChannelMessageEvent
+	pub fn recv(&mut self) -> Option<T> {
+		self.data.borrow().with_mut(|d| d.queue.pop_front())
+	}
+
+	/// Returns the number of messages that have not been received yet
+	pub fn len(&self) -> uint {
+		self.data.borrow().with(|d| d.queue.len())
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl<T> Drop for LocalReceiver<T> {
+	fn drop(&mut self) {
+		self.data.borrow().with_mut(|d| {
+			d.receiver_alive = false;
+			d.queue.clear();
+		});
+		self.remove_pending_events();
+	}
+}
+
+pub struct LocalTransmitter<T> {
+	priv data: Rc<RefCell<LocalChannelData<T>>>,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl<T> LocalTransmitter<T> {
+	/// Queues a message and a ChannelMessageEvent. Hands the message back if the receiver is gone.
+	pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+		let alive = self.data.borrow().with(|d| d.receiver_alive);
+		if !alive {
+			return Err(SendError(t));
+		}
+		self.data.borrow().with_mut(|d| d.queue.push_back(t));
+		self.push_event(events::ChannelMessageEvent);
+		Ok(())
+	}
+
+	pub fn is_closed(&self) -> bool {
+		self.data.borrow().with(|d| !d.receiver_alive)
+	}
+
+	fn push_event(&self, event_type: events::EventKind) {
+		self.event_queue.borrow().with_mut(|q|
+			q.push_back_event(events::Event{
+				event_type: event_type,
+				is_valid: true,
+				source_info: self.event_source_info.clone()
+			})
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl<T> Drop for LocalTransmitter<T> {
+	fn drop(&mut self) {
+		let (last, alive) = self.data.borrow().with_mut(|d| {
+			d.nr_transmitters -= 1;
+			(d.nr_transmitters == 0, d.receiver_alive)
+		});
+		if last && alive {
+			self.push_event(events::ChannelClosedEvent);
+		}
+	}
+}
+
+impl<T> Clone for LocalTransmitter<T> {
+	fn clone(&self) -> LocalTransmitter<T> {
+		self.data.borrow().with_mut(|d| d.nr_transmitters += 1);
+		LocalTransmitter{
+			data: self.data.clone(),
+			event_queue: self.event_queue.clone(),
+			event_source_info: self.event_source_info.clone()
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use channel::SendError;
+	use super::LocalChannel;
+
+	fn pending(queue: &EventQueue) -> uint {
+		queue._get_impl().borrow().with(|q| q.pending_events())
+	}
+
+	#[test]
+	fn send_queues_the_event_directly() {
+		let mut queue = EventQueue::new();
+		let (mut rx, tx) = LocalChannel::<int>::create(&queue);
+		assert!(tx.send(1).is_ok());
+		assert!(tx.send(2).is_ok());
+		// No poll is needed to see the events
+		assert_eq!(pending(&queue), 2);
+		assert_eq!(rx.len(), 2);
+		for i in range(1, 3) {
+			let event = queue.next_event().unwrap();
+			match event.event_type {
+				events::ChannelMessageEvent => {},
+				_ => fail!("Expected a ChannelMessageEvent")
+			}
+			assert!(event.originates_from(&rx));
+			assert_eq!(rx.recv(), Some(i));
+		}
+		assert!(rx.recv().is_none());
+	}
+
+	#[test]
+	fn closed_event_after_the_last_transmitter() {
+		let mut queue = EventQueue::new();
+		let (rx, tx) = LocalChannel::<int>::create(&queue);
+		let other = tx.clone();
+		drop(tx);
+		assert_eq!(pending(&queue), 0);
+		drop(other);
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::ChannelClosedEvent => {},
+			_ => fail!("Expected a ChannelClosedEvent")
+		}
+		assert!(event.originates_from(&rx));
+	}
+
+	#[test]
+	fn dropped_receiver_removes_its_events() {
+		let queue = EventQueue::new();
+		let (rx, tx) = LocalChannel::<int>::create(&queue);
+		assert!(tx.send(1).is_ok());
+		drop(rx);
+		assert_eq!(pending(&queue), 0);
+		assert!(tx.is_closed());
+		match tx.send(2) {
+			Err(SendError(2)) => {},
+			_ => fail!("Expected a SendError")
+		}
+	}
+}
@@ -0,0 +1,241 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::libc;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::unstable::mutex::Mutex;
+use std::sync::arc::UnsafeArc;
+
+use super::events;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::{EventQueueImpl, RemoteWaker};
+
+#[deriving(Eq)]
+enum OneshotState {
+	Waiting,
+	Completed,
+	Canceled,
+	ReceiverGone
+}
+
+struct SharedOneshotData<T> {
+	mutex: Mutex,
+	value: Option<T>,
+	state: OneshotState,
+	waker: RemoteWaker,
+	callback: uint
+}
+
+/**
+ * A channel for a single value, e.g. the reply to a request.
+ * It doesn't need an own eventfd. The sender wakes the receiving EventQueue
+ * through the queue's shared RemoteWaker.
+ */
+pub struct Oneshot<T>;
+
+impl <T:Send> Oneshot<T> {
+	pub fn create(event_queue: &EventQueue) -> (~OneshotReceiver<T>, OneshotSender<T>) {
+		let waker = event_queue._get_impl().borrow().with_mut(|q| q.remote_waker());
+		let data = UnsafeArc::new(SharedOneshotData {
+			mutex: unsafe { Mutex::new() },
+			value: None,
+			state: Waiting,
+			waker: waker,
+			callback: 0
+		});
+		let receiver = ~OneshotReceiver {
+			process_func: OneshotReceiver::<T>::process_epoll_events,
+			data: data.clone(),
+			event_queue: event_queue._get_impl(),
+			event_source_info: Rc::new(events::EventSourceInfo::new())
+		};
+		// The callback is only known once the receiver is boxed and nobody else has the data yet
+		let callback: *libc::c_void = unsafe { cast::transmute(&receiver.process_func) };
+		unsafe { (*data.get()).callback = callback as uint; }
+		(receiver, OneshotSender{data: data})
+	}
+}
+
+pub struct OneshotSender<T> {
+	priv data: UnsafeArc<SharedOneshotData<T>>
+}
+
+impl<T:Send> OneshotSender<T> {
+	/// Completes the oneshot. Returns the value if the receiver is gone.
+	pub fn send(self, t: T) -> Result<(), T> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			if (*data).state != Waiting {
+				(*data).mutex.unlock();
+				return Err(t);
+			}
+			(*data).value = Some(t);
+			(*data).state = Completed;
+			(*data).waker.wake((*data).callback as *libc::c_void);
+			(*data).mutex.unlock();
+		}
+		Ok(())
+	}
+
+	/// Returns true if the receiver was dropped and nobody waits for the value anymore
+	pub fn is_canceled(&self) -> bool {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let canceled = (*data).state == ReceiverGone;
+			(*data).mutex.unlock();
+			canceled
+		}
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for OneshotSender<T> {
+	fn drop(&mut self) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			if (*data).state == Waiting {
+				(*data).state = Canceled;
+				(*data).waker.wake((*data).callback as *libc::c_void);
+			}
+			(*data).mutex.unlock();
+		}
+	}
+}
+
+/**
+ * The receiving side of a oneshot.
+ * Queues a OneshotCompletedEvent when the value was sent or a
+ * OneshotCanceledEvent when the sender was dropped without sending.
+ */
+pub struct OneshotReceiver<T> {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv data: UnsafeArc<SharedOneshotData<T>>,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl<T> events::EventSource for OneshotReceiver<T> {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl<T:Send> OneshotReceiver<T> {
+	/// Takes the value after a OneshotCompletedEvent
+	pub fn recv(&mut self) -> Option<T> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let ret = (*data).value.take();
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, _epoll_events: u32) {
+		unsafe {
+			let receiver: *mut OneshotReceiver<T> = func_ptr as *mut OneshotReceiver<T>;
+			let data = (*receiver).data.get();
+
+			(*data).mutex.lock();
+			let state = (*data).state;
+			(*data).mutex.unlock();
+
+			let event_type = match state {
+				Completed => events::OneshotCompletedEvent,
+				_ => events::OneshotCanceledEvent
+			};
+			event_queue.push_back_event(events::Event {
+				event_type: event_type,
+				is_valid: true,
+				source_info: (*receiver).event_source_info.clone()
+			});
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for OneshotReceiver<T> {
+	fn drop(&mut self) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			if (*data).state == Waiting {
+				(*data).state = ReceiverGone;
+			}
+			(*data).mutex.unlock();
+			// No wakeups can be scheduled after the state change
+			(*data).waker.cancel((*data).callback as *libc::c_void);
+		}
+		self.remove_pending_events();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use super::Oneshot;
+
+	#[test]
+	fn completed_event_delivers_the_value() {
+		let mut queue = EventQueue::new();
+		let (mut rx, tx) = Oneshot::<int>::create(&queue);
+		assert!(tx.send(5).is_ok());
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::OneshotCompletedEvent => {},
+			_ => fail!("Expected a OneshotCompletedEvent")
+		}
+		assert!(event.originates_from(rx));
+		assert_eq!(rx.recv(), Some(5));
+		assert!(rx.recv().is_none());
+	}
+
+	#[test]
+	fn dropped_sender_cancels() {
+		let mut queue = EventQueue::new();
+		let (mut rx, tx) = Oneshot::<int>::create(&queue);
+		drop(tx);
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::OneshotCanceledEvent => {},
+			_ => fail!("Expected a OneshotCanceledEvent")
+		}
+		assert!(event.originates_from(rx));
+		assert!(rx.recv().is_none());
+	}
+
+	#[test]
+	fn sender_sees_dropped_receiver() {
+		let queue = EventQueue::new();
+		let (rx, tx) = Oneshot::<int>::create(&queue);
+		assert!(!tx.is_canceled());
+		drop(rx);
+		assert!(tx.is_canceled());
+		match tx.send(1) {
+			Err(1) => {},
+			_ => fail!("Expected the value back")
+		}
+	}
+}
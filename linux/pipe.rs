@@ -0,0 +1,691 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::io;
+use std::libc;
+use std::os;
+use std::io::IoError;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::util;
+use std::unstable::mutex::{Mutex, MUTEX_INIT};
+
+use super::IoResult;
+use super::events;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::{EventQueueImpl, FdOwner};
+use super::syscalls;
+use super::helpers;
+
+fn closed_error() -> IoError {
+	IoError{
+		kind: io::Closed,
+		desc: "Pipe is closed",
+		detail: None
+	}
+}
+
+// Protects the following counters, which are shared by all threads
+static mut STDIO_LOCK: Mutex = MUTEX_INIT;
+// Number of live Stdio readers and writers in the process
+static mut STDIO_HANDLES: uint = 0;
+// Whether a standard stream was switched to non-blocking mode
+static mut STDIO_NONBLOCKING: [bool, ..3] = [false, ..3];
+
+/// Registers a Stdio handle for the standard stream `std_fd`
+fn acquire_stdio(std_fd: i32) {
+	unsafe {
+		STDIO_LOCK.lock();
+		STDIO_HANDLES += 1;
+		STDIO_NONBLOCKING[std_fd as uint] = true;
+		STDIO_LOCK.unlock();
+	}
+}
+
+/**
+ * Removes a Stdio handle. The standard streams are only switched back to
+ * blocking mode when the last handle is gone, because the duplicated fds
+ * share their mode with the original fds and with each other if they
+ * refer to the same terminal.
+ */
+fn release_stdio() {
+	unsafe {
+		STDIO_LOCK.lock();
+		STDIO_HANDLES -= 1;
+		if STDIO_HANDLES == 0 {
+			for std_fd in range(0u, 3) {
+				if STDIO_NONBLOCKING[std_fd] {
+					syscalls::set_fd_blocking(std_fd as i32, true);
+					STDIO_NONBLOCKING[std_fd] = false;
+				}
+			}
+		}
+		STDIO_LOCK.unlock();
+	}
+}
+
+pub struct Pipe;
+
+impl Pipe {
+	/// Creates an anonymous pipe whose ends are both used on the EventQueue
+	pub fn create(event_queue: &EventQueue) -> IoResult<(~PipeReader, ~PipeWriter)> {
+		let mut fds = [-1i32, -1i32];
+		if unsafe { syscalls::pipe2(fds.as_mut_ptr(), syscalls::O_CLOEXEC) } == -1 {
+			return Err(helpers::last_error());
+		}
+		let reader = match unsafe { PipeReader::from_fd(fds[0], event_queue) } {
+			Ok(reader) => reader,
+			Err(err) => {
+				unsafe { libc::close(fds[1]); }
+				return Err(err);
+			}
+		};
+		let writer = if_ok!(unsafe { PipeWriter::from_fd(fds[1], event_queue) });
+		Ok((reader, writer))
+	}
+}
+
+/**
+ * Evented access to the standard streams of the process.
+ * The streams are duplicated and switched to non-blocking mode, which also
+ * affects other processes that share them, e.g. the shell for a terminal.
+ * Blocking mode is restored when the last reader or writer is dropped.
+ */
+pub struct Stdio;
+
+impl Stdio {
+	pub fn stdin(event_queue: &EventQueue) -> IoResult<~PipeReader> {
+		let fd = if_ok!(Stdio::dup_fd(libc::STDIN_FILENO));
+		let mut reader = if_ok!(unsafe { PipeReader::from_fd(fd, event_queue) });
+		acquire_stdio(libc::STDIN_FILENO);
+		reader.restore_blocking = true;
+		Ok(reader)
+	}
+
+	pub fn stdout(event_queue: &EventQueue) -> IoResult<~PipeWriter> {
+		Stdio::writer(libc::STDOUT_FILENO, event_queue)
+	}
+
+	pub fn stderr(event_queue: &EventQueue) -> IoResult<~PipeWriter> {
+		Stdio::writer(libc::STDERR_FILENO, event_queue)
+	}
+
+	fn writer(std_fd: i32, event_queue: &EventQueue) -> IoResult<~PipeWriter> {
+		let fd = if_ok!(Stdio::dup_fd(std_fd));
+		let mut writer = if_ok!(unsafe { PipeWriter::from_fd(fd, event_queue) });
+		acquire_stdio(std_fd);
+		writer.restore_blocking = true;
+		Ok(writer)
+	}
+
+	fn dup_fd(fd: i32) -> IoResult<i32> {
+		match unsafe { libc::dup(fd) } {
+			-1 => Err(helpers::last_error()),
+			fd => Ok(fd)
+		}
+	}
+}
+
+/**
+ * The reading end of a pipe, a terminal or a file.
+ * Queues a DataAvailableEvent with the number of readable bytes and a
+ * StreamClosedEvent when all writers are closed and everything was read.
+ * After a DataAvailableEvent no further events are queued until the
+ * announced bytes were read.
+ * Regular files can't be watched by epoll. They are always ready, so the
+ * next DataAvailableEvent is queued directly after everything was read.
+ */
+pub struct PipeReader {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv fd: i32,
+	priv available_bytes: uint,
+	priv closed: bool,
+	priv pollable: bool,
+	priv restore_blocking: bool,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl events::EventSource for PipeReader {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl PipeReader {
+	/**
+	 * Takes ownership of the file descriptor and switches it to non-blocking mode.
+	 * The file descriptor is closed if an error is returned.
+	 */
+	pub unsafe fn from_fd(fd: i32, event_queue: &EventQueue) -> IoResult<~PipeReader> {
+		syscalls::set_fd_blocking(fd, false);
+		let mut reader = ~PipeReader{
+			process_func: PipeReader::process_epoll_events,
+			fd: fd,
+			available_bytes: 0,
+			closed: false,
+			pollable: true,
+			restore_blocking: false,
+			event_queue: event_queue._get_impl(),
+			event_source_info: Rc::new(events::EventSourceInfo::new())
+		};
+		let callback: *libc::c_void = cast::transmute(&reader.process_func);
+		let res = reader.event_queue.borrow().with_mut(|q|
+			q.try_register_fd(fd, syscalls::EPOLLIN | syscalls::EPOLLONESHOT, callback)
+		);
+		match res {
+			Ok(()) => {},
+			Err(ref err) if err.kind == io::PermissionDenied => { // EPERM for regular files
+				reader.pollable = false;
+				reader.update_file_state();
+			},
+			Err(err) => {
+				reader.closed = true;
+				return Err(err);
+			}
+		}
+		Ok(reader)
+	}
+
+	pub fn fd(&self) -> i32 {
+		self.fd
+	}
+
+	/// Reads at most the number of bytes that were announced by events
+	pub fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+		if self.closed {
+			return Err(closed_error());
+		}
+		if self.available_bytes == 0 || buf.len() == 0 {
+			return Ok(0);
+		}
+		let len = if buf.len() < self.available_bytes { buf.len() } else { self.available_bytes };
+		let fd = self.fd;
+		let ret = helpers::retry(|| unsafe {
+			libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, len as libc::size_t) as libc::c_int
+		});
+		if ret < 0 {
+			let errno = os::errno() as int;
+			if errno != libc::EWOULDBLOCK as int && errno != libc::EAGAIN as int {
+				return Err(helpers::last_error());
+			}
+			self.available_bytes = 0;
+		}
+		else if ret == 0 {
+			self.available_bytes = 0;
+		}
+		else {
+			self.available_bytes -= ret as uint;
+		}
+		if self.available_bytes == 0 {
+			if self.pollable {
+				self.rearm();
+			}
+			else {
+				self.update_file_state();
+			}
+		}
+		Ok(if ret > 0 { ret as uint } else { 0 })
+	}
+
+	/// Enables the next event after everything that was announced was read
+	fn rearm(&mut self) {
+		let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+		let fd = self.fd;
+		self.event_queue.borrow().with_mut(|q|
+			q.modify_fd(fd, syscalls::EPOLLIN | syscalls::EPOLLONESHOT, callback)
+		);
+	}
+
+	fn update_file_state(&mut self) {
+		let event_queue = self.event_queue.clone();
+		event_queue.borrow().with_mut(|q| self.update_available_bytes(q, false));
+	}
+
+	/**
+	 * Queues a DataAvailableEvent if there is something to read.
+	 * Otherwise the reader is closed on a hangup or at the end of a file
+	 * or waits for the next epoll event.
+	 */
+	fn update_available_bytes(&mut self, event_queue: &mut EventQueueImpl, hangup: bool) {
+		let bytes_available: i32 = 0;
+		let fd = self.fd;
+		let ret = helpers::retry(|| unsafe {
+			syscalls::ioctl(fd, syscalls::FIONREAD, &bytes_available)
+		});
+		if ret == 0 && bytes_available > 0 {
+			// A pollable fd stays disabled until the bytes were read
+			self.available_bytes = bytes_available as uint;
+			event_queue.push_back_event(events::Event {
+				event_type: events::DataAvailableEvent(bytes_available as uint),
+				is_valid: true,
+				source_info: self.event_source_info.clone()
+			});
+		}
+		else if ret != 0 || hangup || !self.pollable {
+			if ret != 0 {
+				event_queue.push_back_event(events::Event {
+					event_type: events::IoErrorEvent(helpers::last_error()),
+					is_valid: true,
+					source_info: self.event_source_info.clone()
+				});
+			}
+			self.closed = true;
+			if self.pollable {
+				event_queue.unregister_fd(fd);
+			}
+			event_queue.push_back_event(events::Event {
+				event_type: events::StreamClosedEvent,
+				is_valid: true,
+				source_info: self.event_source_info.clone()
+			});
+		}
+		else {
+			let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+			event_queue.modify_fd(fd, syscalls::EPOLLIN | syscalls::EPOLLONESHOT, callback);
+		}
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let reader: *mut PipeReader = func_ptr as *mut PipeReader;
+			if (*reader).closed { return; }
+			let hangup = epoll_events & (syscalls::EPOLLHUP | syscalls::EPOLLERR) != 0;
+			(*reader).update_available_bytes(event_queue, hangup);
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl Drop for PipeReader {
+	fn drop(&mut self) {
+		let fd = self.fd;
+		if !self.closed && self.pollable {
+			self.event_queue.borrow().with_mut(|q| q.unregister_fd(fd));
+		}
+		if self.restore_blocking {
+			release_stdio();
+		}
+		unsafe { libc::close(fd); }
+		self.remove_pending_events();
+	}
+}
+
+/**
+ * The writing end of a pipe, a terminal or a file.
+ * `write` never blocks: data that can't be written immediately is buffered
+ * and written when the fd gets writable. A WriteCompletedEvent is queued
+ * when the buffer was written completely. A StreamClosedEvent is queued
+ * when the reading end was closed, the buffered data is lost then.
+ * Data that is still buffered when the writer is dropped is written by the
+ * EventQueue, which closes the fd afterwards. The standard streams are
+ * flushed blocking instead.
+ */
+pub struct PipeWriter {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv fd: i32,
+	priv buffer: ~[u8],
+	priv buffer_pos: uint, // Bytes at the start of the buffer that were already written
+	priv closed: bool,
+	priv pollable: bool,
+	priv restore_blocking: bool,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl events::EventSource for PipeWriter {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl PipeWriter {
+	/**
+	 * Takes ownership of the file descriptor and switches it to non-blocking mode.
+	 * The file descriptor is closed if an error is returned.
+	 */
+	pub unsafe fn from_fd(fd: i32, event_queue: &EventQueue) -> IoResult<~PipeWriter> {
+		syscalls::set_fd_blocking(fd, false);
+		let mut writer = ~PipeWriter{
+			process_func: PipeWriter::process_epoll_events,
+			fd: fd,
+			buffer: ~[],
+			buffer_pos: 0,
+			closed: false,
+			pollable: true,
+			restore_blocking: false,
+			event_queue: event_queue._get_impl(),
+			event_source_info: Rc::new(events::EventSourceInfo::new())
+		};
+		// Without interest in EPOLLOUT only errors are reported,
+		// e.g. when the reading end is closed
+		let callback: *libc::c_void = cast::transmute(&writer.process_func);
+		let res = writer.event_queue.borrow().with_mut(|q|
+			q.try_register_fd(fd, 0, callback)
+		);
+		match res {
+			Ok(()) => {},
+			Err(ref err) if err.kind == io::PermissionDenied => { // EPERM for regular files
+				writer.pollable = false;
+			},
+			Err(err) => {
+				writer.closed = true;
+				return Err(err);
+			}
+		}
+		Ok(writer)
+	}
+
+	pub fn fd(&self) -> i32 {
+		self.fd
+	}
+
+	/// Writes or buffers all bytes
+	pub fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+		if self.closed {
+			return Err(closed_error());
+		}
+		if self.pending_bytes() != 0 {
+			self.buffer.push_all(buf);
+			return Ok(());
+		}
+
+		let mut written = 0;
+		loop {
+			if written == buf.len() {
+				return Ok(());
+			}
+			match helpers::write_fd(self.fd, buf.slice_from(written)) {
+				Ok(0) if self.pollable => break,
+				Ok(n) => written += n,
+				Err(err) => {
+					self.close();
+					return Err(err);
+				}
+			}
+		}
+		self.buffer.push_all(buf.slice_from(written));
+		self.set_interest(syscalls::EPOLLOUT);
+		Ok(())
+	}
+
+	/// Returns the number of bytes that were not written yet
+	pub fn pending_bytes(&self) -> uint {
+		self.buffer.len() - self.buffer_pos
+	}
+
+	/// Closes the fd, so that the reading end sees the end of the stream. Buffered data is discarded.
+	pub fn close(&mut self) {
+		if self.fd == -1 { return; }
+		let fd = self.fd;
+		if !self.closed && self.pollable {
+			self.event_queue.borrow().with_mut(|q| q.unregister_fd(fd));
+		}
+		self.closed = true;
+		self.clear_buffer();
+		if self.restore_blocking {
+			release_stdio();
+		}
+		unsafe { libc::close(fd); }
+		self.fd = -1;
+	}
+
+	fn clear_buffer(&mut self) {
+		self.buffer.clear();
+		self.buffer_pos = 0;
+	}
+
+	/// Writes the buffered data, waiting for the fd to get writable
+	fn flush_blocking(&mut self) {
+		while self.pending_bytes() != 0 {
+			match helpers::write_fd(self.fd, self.buffer.slice_from(self.buffer_pos)) {
+				Ok(0) => {
+					// Wait without switching the fd to blocking mode, which
+					// would affect the other handles to the same stream
+					let mut pfd = syscalls::pollfd { fd: self.fd, events: syscalls::POLLOUT, revents: 0 };
+					if helpers::retry(|| unsafe { syscalls::poll(&mut pfd, 1, -1) }) == -1 {
+						break;
+					}
+				},
+				Ok(n) => self.buffer_pos += n,
+				Err(_) => break
+			}
+		}
+		self.clear_buffer();
+	}
+
+	fn set_interest(&mut self, flags: u32) {
+		let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+		let fd = self.fd;
+		self.event_queue.borrow().with_mut(|q| q.modify_fd(fd, flags, callback));
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let writer: *mut PipeWriter = func_ptr as *mut PipeWriter;
+			if (*writer).closed { return; }
+			let callback: *libc::c_void = cast::transmute(&(*writer).process_func);
+
+			let mut result = Ok(());
+			if epoll_events & (syscalls::EPOLLHUP | syscalls::EPOLLERR) != 0 {
+				result = Err(IoError{
+					kind: io::BrokenPipe,
+					desc: "broken pipe",
+					detail: None
+				});
+			}
+			else if epoll_events & syscalls::EPOLLOUT != 0 {
+				match helpers::write_fd((*writer).fd, (*writer).buffer.slice_from((*writer).buffer_pos)) {
+					Ok(n) => {
+						(*writer).buffer_pos += n;
+						if (*writer).pending_bytes() == 0 {
+							(*writer).clear_buffer();
+							event_queue.modify_fd((*writer).fd, 0, callback);
+							event_queue.push_back_event(events::Event {
+								event_type: events::WriteCompletedEvent,
+								is_valid: true,
+								source_info: (*writer).event_source_info.clone()
+							});
+						}
+						else if (*writer).buffer_pos > (*writer).buffer.len() / 2 {
+							// Drop the written part once it outweighs the rest,
+							// so that each byte is copied at most once on average
+							(*writer).buffer = (*writer).buffer.slice_from((*writer).buffer_pos).to_owned();
+							(*writer).buffer_pos = 0;
+						}
+					},
+					Err(err) => result = Err(err)
+				}
+			}
+
+			match result {
+				Err(err) => {
+					if err.kind != io::BrokenPipe {
+						event_queue.push_back_event(events::Event {
+							event_type: events::IoErrorEvent(err),
+							is_valid: true,
+							source_info: (*writer).event_source_info.clone()
+						});
+					}
+					(*writer).closed = true;
+					(*writer).clear_buffer();
+					event_queue.unregister_fd((*writer).fd);
+					event_queue.push_back_event(events::Event {
+						event_type: events::StreamClosedEvent,
+						is_valid: true,
+						source_info: (*writer).event_source_info.clone()
+					});
+				},
+				Ok(()) => {}
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl Drop for PipeWriter {
+	fn drop(&mut self) {
+		if !self.closed && self.pending_bytes() != 0 {
+			if self.restore_blocking {
+				// Don't lose output to the standard streams
+				self.flush_blocking();
+			}
+			else {
+				// Keep the fd open until the EventQueue has written the rest
+				let fd = self.fd;
+				let lingering = ~LingeringWrite {
+					fd: fd,
+					data: util::replace(&mut self.buffer, ~[]),
+					offset: self.buffer_pos
+				};
+				let mut refmut = self.event_queue.borrow().borrow_mut();
+				refmut.get().unregister_fd(fd);
+				// On error the data is lost like on close
+				let _ = refmut.get().add_owned_fd(fd, syscalls::EPOLLOUT, lingering as ~FdOwner:);
+				self.closed = true;
+				self.buffer_pos = 0;
+				self.fd = -1;
+			}
+		}
+		self.close();
+		self.remove_pending_events();
+	}
+}
+
+/**
+ * The unwritten data of a dropped PipeWriter. The EventQueue writes it
+ * when the fd gets writable and closes the fd after everything was written
+ * or writing failed. Data that is left when the EventQueue is dropped is lost.
+ */
+struct LingeringWrite {
+	fd: i32,
+	data: ~[u8],
+	// Number of bytes that were written
+	offset: uint
+}
+
+impl FdOwner for LingeringWrite {
+	fn process(&mut self, _event_queue: &mut EventQueueImpl, _epoll_events: u32) -> bool {
+		match helpers::write_fd(self.fd, self.data.slice_from(self.offset)) {
+			Ok(n) => {
+				self.offset += n;
+				self.offset < self.data.len()
+			},
+			Err(_) => false
+		}
+	}
+}
+
+impl Drop for LingeringWrite {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.fd); }
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use super::Pipe;
+
+	static TOTAL: uint = 200000; // More than the pipe capacity
+
+	#[test]
+	fn written_data_is_announced() {
+		let mut queue = EventQueue::new();
+		let (mut reader, mut writer) = Pipe::create(&queue).unwrap();
+		writer.write([1u8, 2, 3]).unwrap();
+		assert_eq!(writer.pending_bytes(), 0);
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::DataAvailableEvent(3) => {},
+			_ => fail!("Expected a DataAvailableEvent")
+		}
+		assert!(event.originates_from(reader));
+		let mut buf = [0u8, ..8];
+		assert_eq!(reader.read(buf).unwrap(), 3);
+		assert!(buf.slice_to(3).to_owned() == ~[1u8, 2, 3]);
+	}
+
+	#[test]
+	fn buffered_data_is_completed() {
+		let mut queue = EventQueue::new();
+		let (mut reader, mut writer) = Pipe::create(&queue).unwrap();
+		writer.write(vec_of_len(TOTAL)).unwrap();
+		assert!(writer.pending_bytes() > 0);
+		let mut received = 0;
+		let mut completed = false;
+		let mut buf = [0u8, ..4096];
+		while received < TOTAL || !completed {
+			let event = queue.next_event().unwrap();
+			match event.event_type {
+				events::DataAvailableEvent(_) => {
+					loop {
+						let n = reader.read(buf).unwrap();
+						if n == 0 { break; }
+						received += n;
+					}
+				},
+				events::WriteCompletedEvent => {
+					assert!(event.originates_from(writer));
+					completed = true;
+				},
+				_ => fail!("Unexpected event")
+			}
+		}
+		assert_eq!(writer.pending_bytes(), 0);
+	}
+
+	#[test]
+	fn dropped_writer_is_flushed() {
+		let mut queue = EventQueue::new();
+		let (mut reader, mut writer) = Pipe::create(&queue).unwrap();
+		writer.write(vec_of_len(TOTAL)).unwrap();
+		assert!(writer.pending_bytes() > 0);
+		drop(writer);
+		let mut received = 0;
+		let mut buf = [0u8, ..4096];
+		loop {
+			let event = queue.next_event().unwrap();
+			match event.event_type {
+				events::DataAvailableEvent(_) => {
+					loop {
+						let n = reader.read(buf).unwrap();
+						if n == 0 { break; }
+						received += n;
+					}
+				},
+				events::StreamClosedEvent => break,
+				_ => fail!("Unexpected event")
+			}
+		}
+		assert_eq!(received, TOTAL);
+	}
+
+	fn vec_of_len(len: uint) -> ~[u8] {
+		::std::vec::from_elem(len, 7u8)
+	}
+}
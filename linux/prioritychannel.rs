@@ -0,0 +1,489 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::libc;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::unstable::mutex::Mutex;
+use std::sync::arc::UnsafeArc;
+use collections::ringbuf::RingBuf;
+use collections::deque::Deque;
+use collections::treemap::TreeMap;
+
+use super::events;
+use super::channel::{SendError, TryRecvResult, Empty, Disconnected, Data};
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::syscalls;
+use super::helpers;
+
+pub struct BlockingPriorityReceiver<T> {
+	priv data: UnsafeArc<SharedPriorityData<T>>
+}
+
+pub struct PriorityTransmitter<T> {
+	priv data: UnsafeArc<SharedPriorityData<T>>
+}
+
+struct SharedPriorityData<T> {
+	// One FIFO per priority level. Empty levels are removed.
+	queues: TreeMap<uint, RingBuf<T>>,
+	len: uint,
+	mutex: Mutex,
+	nr_senders: uint,
+	port_alive: bool,
+	receiver_waiting: bool,
+	receiver_notified: bool,
+	epoll_fd: i32
+}
+
+/**
+ * A channel that delivers messages with a higher priority first.
+ * Messages with the same priority are delivered in the order they were sent.
+ * `send` uses priority 0, the lowest one.
+ */
+pub struct PriorityChannel<T>;
+
+impl <T:Send> PriorityChannel<T> {
+	pub fn create_blocking() -> (BlockingPriorityReceiver<T>, PriorityTransmitter<T>) {
+		let shared_data: UnsafeArc<SharedPriorityData<T>>
+			= UnsafeArc::new(SharedPriorityData {
+				queues: TreeMap::new(),
+				len: 0,
+				mutex: unsafe { Mutex::new() },
+				nr_senders: 1,
+				port_alive: true,
+				receiver_waiting: false,
+				receiver_notified: false,
+				epoll_fd: -1
+		});
+		(BlockingPriorityReceiver{data: shared_data.clone()}, PriorityTransmitter{data: shared_data})
+	}
+
+	pub fn create(event_queue: &EventQueue) -> (~PriorityReceiver<T>, PriorityTransmitter<T>) {
+		let (rx,tx) = PriorityChannel::<T>::create_blocking();
+		(PriorityReceiver::from_blocking_receiver(rx, event_queue), tx)
+	}
+}
+
+/// Wakes the receiver. Must be called with the mutex held.
+unsafe fn notify_receiver<T>(data: *mut SharedPriorityData<T>) {
+	if (*data).epoll_fd == -1 {
+		if (*data).receiver_waiting {
+			(*data).mutex.signal();
+		}
+	}
+	else if !(*data).receiver_notified {
+		(*data).receiver_notified = true;
+		if helpers::signal_eventfd((*data).epoll_fd) == -1 {
+			(*data).mutex.unlock();
+			fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
+		}
+	}
+}
+
+/// Must be called with the mutex held
+unsafe fn highest_priority<T>(data: *mut SharedPriorityData<T>) -> Option<uint> {
+	(*data).queues.rev_iter().next().map(|(prio, _)| *prio)
+}
+
+/// Removes the oldest message with the highest priority. Must be called with the mutex held.
+unsafe fn pop_message<T>(data: *mut SharedPriorityData<T>) -> Option<T> {
+	let prio = match highest_priority(data) {
+		Some(prio) => prio,
+		None => return None
+	};
+	let (ret, empty) = {
+		let queue = (*data).queues.find_mut(&prio).unwrap();
+		let ret = queue.pop_front();
+		(ret, queue.len() == 0)
+	};
+	if empty {
+		(*data).queues.remove(&prio);
+	}
+	(*data).len -= 1;
+	ret
+}
+
+impl<T:Send> BlockingPriorityReceiver<T> {
+	pub fn recv(&self) -> T {
+		match self.recv_opt() {
+			Some(t) => t,
+			None => fail!("Remote channels are dead") // Sender(s) dead
+		}
+	}
+
+	/// Returns None when all senders are gone and all messages were received
+	pub fn recv_opt(&self) -> Option<T> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			while (*data).len == 0 && (*data).nr_senders != 0 {
+				(*data).receiver_waiting = true;
+				(*data).mutex.wait();
+				(*data).receiver_waiting = false;
+			}
+			let ret = pop_message(data);
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+
+	pub fn try_recv(&self) -> TryRecvResult<T> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let senders_alive = (*data).nr_senders != 0;
+			let ret = match pop_message(data) {
+				Some(t) => Data(t),
+				None if !senders_alive => Disconnected,
+				None => Empty
+			};
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+
+	/// Returns the priority of the message that the next recv will return
+	pub fn highest_priority(&self) -> Option<uint> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let ret = highest_priority(data);
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for BlockingPriorityReceiver<T> {
+	fn drop(&mut self) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).port_alive = false;
+			(*data).queues.clear();
+			(*data).len = 0;
+			(*data).mutex.unlock();
+		}
+	}
+}
+
+/**
+ * The evented receiver of a priority channel.
+ * Queues a ChannelPriorityMessageEvent for each message. The event contains
+ * the highest priority that was pending when the event was queued, `recv`
+ * always returns the message with the highest priority at the time of the call.
+ */
+pub struct PriorityReceiver<T> {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv receiver: BlockingPriorityReceiver<T>,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>,
+	priv available_messages: uint,
+	priv urgent_priority: Option<uint>,
+	priv closed: bool
+}
+
+impl<T> events::EventSource for PriorityReceiver<T> {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl<T:Send> PriorityReceiver<T> {
+	pub fn from_blocking_receiver(blocking_receiver: BlockingPriorityReceiver<T>, event_queue: &EventQueue) -> ~PriorityReceiver<T> {
+		let fd = unsafe { syscalls::eventfd(0, 0) };
+		if fd == -1 {
+			fail!("Creating eventfd for port failed: {}", helpers::last_error().desc);
+		}
+
+		let receiver = ~PriorityReceiver{
+			receiver: blocking_receiver,
+			event_queue: event_queue._get_impl(),
+			process_func: PriorityReceiver::<T>::process_epoll_events,
+			event_source_info: Rc::new(events::EventSourceInfo::new()),
+			available_messages: 0,
+			urgent_priority: None,
+			closed: false
+		};
+
+		let callback: *libc::c_void = unsafe { cast::transmute(&receiver.process_func) };
+		receiver.event_queue.borrow().with_mut(|q|
+			q.register_fd(fd, syscalls::EPOLLIN, callback)
+		);
+
+		let data = receiver.receiver.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).epoll_fd = fd;
+			(*data).receiver_notified = false;
+			if (*data).len > 0 || (*data).nr_senders == 0 {
+				notify_receiver(data);
+			}
+			(*data).mutex.unlock();
+		}
+		receiver
+	}
+
+	/// Fetches the message with the highest priority
+	pub fn recv(&mut self) -> Option<T> {
+		if self.available_messages == 0 {
+			return None;
+		}
+		self.available_messages -= 1;
+		let data = self.receiver.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let ret = pop_message(data);
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+
+	/**
+	 * Events for messages with at least the given priority are queued at the
+	 * front of the EventQueue, so that they are handled before the events
+	 * of all other sources.
+	 */
+	pub fn set_urgent_priority(&mut self, priority: Option<uint>) {
+		self.urgent_priority = priority;
+	}
+
+	pub fn get_urgent_priority(&self) -> Option<uint> {
+		self.urgent_priority
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let receiver: *mut PriorityReceiver<T> = func_ptr as *mut PriorityReceiver<T>;
+			let data = (*receiver).receiver.data.get();
+
+			if epoll_events & syscalls::EPOLLIN != 0 {
+				let buffer = [0, ..8];
+
+				// Only the receiver changes epoll_fd, so it can be read without the mutex
+				let ret = helpers::retry(||
+					libc::read((*data).epoll_fd,
+						       buffer.as_ptr() as *mut libc::c_void,
+						       buffer.len() as libc::size_t) as i32
+				);
+				if ret != 8 { // Must be 8 bytes
+					return;
+				}
+				(*data).mutex.lock();
+				(*data).receiver_notified = false;
+				let len = (*data).len;
+				let highest = highest_priority(data);
+				let senders_alive = (*data).nr_senders != 0;
+				(*data).mutex.unlock();
+
+				let new_messages = len - (*receiver).available_messages;
+				(*receiver).available_messages = len;
+				if new_messages > 0 {
+					let prio = highest.unwrap();
+					let urgent = match (*receiver).urgent_priority {
+						Some(urgent_prio) => prio >= urgent_prio,
+						None => false
+					};
+					for _ in range(0, new_messages) {
+						let e = events::Event {
+							event_type: events::ChannelPriorityMessageEvent(prio),
+							is_valid: true,
+							source_info: (*receiver).event_source_info.clone()
+						};
+						if urgent {
+							event_queue.push_front_event(e);
+						}
+						else {
+							event_queue.push_back_event(e);
+						}
+					}
+				}
+				if !senders_alive && !(*receiver).closed {
+					(*receiver).closed = true;
+					event_queue.push_back_event(events::Event {
+						event_type: events::ChannelClosedEvent,
+						is_valid: true,
+						source_info: (*receiver).event_source_info.clone()
+					});
+				}
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for PriorityReceiver<T> {
+	fn drop(&mut self) {
+		let data = self.receiver.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			if (*data).epoll_fd != -1 {
+				libc::close((*data).epoll_fd);
+				(*data).epoll_fd = -1; // Disable further events from clients
+			}
+			(*data).mutex.unlock();
+		}
+		self.remove_pending_events();
+	}
+}
+
+impl<T:Send> PriorityTransmitter<T> {
+	/// Sends a message with the lowest priority
+	pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+		self.send_with_priority(t, 0)
+	}
+
+	/// Hands the message back if the receiver is gone
+	pub fn send_with_priority(&self, t: T, priority: uint) -> Result<(), SendError<T>> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			if !(*data).port_alive {
+				(*data).mutex.unlock();
+				return Err(SendError(t));
+			}
+			if !(*data).queues.contains_key(&priority) {
+				(*data).queues.insert(priority, RingBuf::new());
+			}
+			(*data).queues.find_mut(&priority).unwrap().push_back(t);
+			(*data).len += 1;
+			notify_receiver(data);
+			(*data).mutex.unlock();
+		}
+		Ok(())
+	}
+
+	pub fn is_closed(&self) -> bool {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let closed = !(*data).port_alive;
+			(*data).mutex.unlock();
+			closed
+		}
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for PriorityTransmitter<T> {
+	fn drop(&mut self) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_senders -= 1;
+			if (*data).nr_senders == 0 {
+				notify_receiver(data);
+			}
+			(*data).mutex.unlock();
+		}
+	}
+}
+
+impl<T:Send> Clone for PriorityTransmitter<T> {
+	fn clone(&self) -> PriorityTransmitter<T> {
+		let new = PriorityTransmitter{data: self.data.clone()};
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_senders += 1;
+			(*data).mutex.unlock();
+		}
+		new
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use super::PriorityChannel;
+
+	fn poll(queue: &EventQueue) {
+		queue._get_impl().borrow().with_mut(|q| q.poll_events()).unwrap();
+	}
+
+	#[test]
+	fn higher_priorities_are_received_first() {
+		let (rx, tx) = PriorityChannel::<int>::create_blocking();
+		assert!(tx.send_with_priority(1, 0).is_ok());
+		assert!(tx.send_with_priority(2, 5).is_ok());
+		assert!(tx.send_with_priority(3, 1).is_ok());
+		assert_eq!(rx.highest_priority(), Some(5));
+		assert_eq!(rx.recv(), 2);
+		assert_eq!(rx.recv(), 3);
+		assert_eq!(rx.recv(), 1);
+		assert_eq!(rx.highest_priority(), None);
+	}
+
+	#[test]
+	fn same_priority_is_fifo() {
+		let (rx, tx) = PriorityChannel::<int>::create_blocking();
+		for i in range(0, 5) {
+			assert!(tx.send_with_priority(i, 3).is_ok());
+			assert!(tx.send(i + 10).is_ok());
+		}
+		for i in range(0, 5) {
+			assert_eq!(rx.recv(), i);
+		}
+		for i in range(0, 5) {
+			assert_eq!(rx.recv(), i + 10);
+		}
+	}
+
+	#[test]
+	fn events_carry_the_highest_pending_priority() {
+		let mut queue = EventQueue::new();
+		let (mut rx, tx) = PriorityChannel::<int>::create(&queue);
+		assert!(tx.send_with_priority(10, 1).is_ok());
+		assert!(tx.send_with_priority(20, 7).is_ok());
+		for _ in range(0, 2) {
+			let event = queue.next_event().unwrap();
+			match event.event_type {
+				events::ChannelPriorityMessageEvent(7) => {},
+				_ => fail!("Expected a ChannelPriorityMessageEvent with priority 7")
+			}
+			assert!(event.originates_from(rx));
+		}
+		assert_eq!(rx.recv(), Some(20));
+		assert_eq!(rx.recv(), Some(10));
+		assert!(rx.recv().is_none());
+		drop(tx);
+		match queue.next_event().unwrap().event_type {
+			events::ChannelClosedEvent => {},
+			_ => fail!("Expected a ChannelClosedEvent")
+		}
+	}
+
+	#[test]
+	fn urgent_events_are_queued_first() {
+		let mut queue = EventQueue::new();
+		let (normal, normal_tx) = PriorityChannel::<int>::create(&queue);
+		let (mut urgent, urgent_tx) = PriorityChannel::<int>::create(&queue);
+		urgent.set_urgent_priority(Some(5));
+		assert!(normal_tx.send_with_priority(1, 9).is_ok());
+		poll(&queue);
+		assert!(urgent_tx.send_with_priority(2, 5).is_ok());
+		poll(&queue);
+		assert!(queue.next_event().unwrap().originates_from(urgent));
+		assert!(queue.next_event().unwrap().originates_from(normal));
+	}
+}
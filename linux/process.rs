@@ -0,0 +1,657 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::libc;
+use std::local_data;
+use std::mem;
+use std::os;
+use std::ptr;
+use std::c_str::{CString, ToCStr};
+use std::io::process::{ProcessExit, ExitStatus, ExitSignal};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::IoResult;
+use super::events;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::{EventQueueImpl, FdOwner};
+use super::pipe::{PipeReader, PipeWriter};
+use super::syscalls;
+use super::helpers;
+
+/// Determines what a standard stream of a child process is connected to
+#[deriving(Eq)]
+pub enum StdioMode {
+	/// The child uses the stream of the parent
+	InheritStdio,
+	/// The stream is available through the Process as a pipe
+	PipedStdio,
+	/// The stream is connected to /dev/null
+	NullStdio
+}
+
+pub struct ProcessOptions {
+	/// The working directory of the child. Defaults to the one of the parent.
+	cwd: Option<Path>,
+	/// The complete environment of the child. Defaults to the one of the parent.
+	env: Option<~[(~str, ~str)]>,
+	stdin: StdioMode,
+	stdout: StdioMode,
+	stderr: StdioMode,
+	/// Puts the child into a new process group, which `kill_group` signals
+	new_process_group: bool,
+	/// Kills the child if it didn't exit after this number of milliseconds
+	timeout: Option<u32>
+}
+
+impl ProcessOptions {
+	/// Returns options with piped standard streams and no timeout
+	pub fn new() -> ProcessOptions {
+		ProcessOptions {
+			cwd: None,
+			env: None,
+			stdin: PipedStdio,
+			stdout: PipedStdio,
+			stderr: PipedStdio,
+			new_process_group: false,
+			timeout: None
+		}
+	}
+}
+
+/// Closes all file descriptors that are not -1
+fn close_fds(fds: &[i32]) {
+	for fd in fds.iter() {
+		if *fd != -1 {
+			unsafe { libc::close(*fd); }
+		}
+	}
+}
+
+/// Wraps the parent ends of the pipes. All of them are closed on error.
+fn open_streams(stdin_fd: i32, stdout_fd: i32, stderr_fd: i32, event_queue: &EventQueue)
+		-> IoResult<(Option<~PipeWriter>, Option<~PipeReader>, Option<~PipeReader>)> {
+	unsafe {
+		let stdin = if stdin_fd != -1 {
+			match PipeWriter::from_fd(stdin_fd, event_queue) {
+				Ok(writer) => Some(writer),
+				Err(err) => {
+					close_fds(&[stdout_fd, stderr_fd]);
+					return Err(err);
+				}
+			}
+		} else { None };
+		let stdout = if stdout_fd != -1 {
+			match PipeReader::from_fd(stdout_fd, event_queue) {
+				Ok(reader) => Some(reader),
+				Err(err) => {
+					close_fds(&[stderr_fd]);
+					return Err(err);
+				}
+			}
+		} else { None };
+		let stderr = if stderr_fd != -1 {
+			Some(if_ok!(PipeReader::from_fd(stderr_fd, event_queue)))
+		} else { None };
+		Ok((stdin, stdout, stderr))
+	}
+}
+
+/**
+ * Creates the ends of a standard stream for the child.
+ * Returns (child end, parent end), both close-on-exec.
+ */
+fn stdio_fds(mode: StdioMode, is_input: bool) -> IoResult<(i32, i32)> {
+	match mode {
+		InheritStdio => Ok((-1, -1)),
+		NullStdio => {
+			let flags = if is_input { libc::O_RDONLY } else { libc::O_WRONLY };
+			let fd = "/dev/null".with_c_str(|path| unsafe {
+				libc::open(path, flags | syscalls::O_CLOEXEC, 0)
+			});
+			if fd == -1 { Err(helpers::last_error()) } else { Ok((fd, -1)) }
+		},
+		PipedStdio => {
+			let mut fds = [-1i32, -1i32];
+			if unsafe { syscalls::pipe2(fds.as_mut_ptr(), syscalls::O_CLOEXEC) } == -1 {
+				return Err(helpers::last_error());
+			}
+			if is_input { Ok((fds[0], fds[1])) } else { Ok((fds[1], fds[0])) }
+		}
+	}
+}
+
+fn translate_status(status: i32) -> ProcessExit {
+	if status & 0x7f == 0 {
+		ExitStatus(((status >> 8) & 0xff) as int)
+	}
+	else {
+		ExitSignal((status & 0x7f) as int)
+	}
+}
+
+/// Reaps the child without blocking. Returns false while it is running.
+fn reap(pid: libc::pid_t) -> bool {
+	let mut status = 0;
+	let ret = helpers::retry(|| unsafe { syscalls::waitpid(pid, &mut status, syscalls::WNOHANG) });
+	// Reaped, or an error like ECHILD that won't go away
+	ret != 0
+}
+
+/**
+ * Receives SIGCHLD through a signalfd for the Processes of an EventQueue on
+ * kernels without pidfds. It is owned by the EventQueue and created on
+ * first use. All watchers are woken for each signal, because several exits
+ * can be merged into one signal.
+ */
+struct ChildSignal {
+	fd: i32,
+	// Address of the EventQueueImpl, the key in CHILD_SIGNALS
+	queue: uint,
+	watchers: ~[*libc::c_void],
+	// Children whose Process was dropped before they exited
+	orphans: ~[libc::pid_t]
+}
+
+// The ChildSignal of each EventQueue of the thread
+local_data_key!(CHILD_SIGNALS: ~[(uint, *mut ChildSignal)])
+
+impl ChildSignal {
+	fn find(event_queue: &mut EventQueueImpl) -> Option<*mut ChildSignal> {
+		let queue = event_queue as *mut EventQueueImpl as uint;
+		local_data::get(CHILD_SIGNALS, |signals| {
+			signals.and_then(|signals|
+				signals.iter().find(|&&(key, _)| key == queue).map(|&(_, signal)| signal))
+		})
+	}
+
+	/**
+	 * Returns the ChildSignal of the queue. SIGCHLD is blocked for the calling
+	 * thread when it is created.
+	 */
+	fn get(event_queue: &mut EventQueueImpl) -> IoResult<*mut ChildSignal> {
+		match ChildSignal::find(event_queue) {
+			Some(signal) => return Ok(signal),
+			None => {}
+		}
+		let mut mask = syscalls::sigset_t::new();
+		unsafe { syscalls::sigaddset(&mut mask, syscalls::SIGCHLD); }
+		if_ok!(helpers::watch_signals(&mask, [syscalls::SIGCHLD as int]));
+		let fd = unsafe { syscalls::signalfd(-1, &mask, syscalls::SFD_NONBLOCK | syscalls::SFD_CLOEXEC) };
+		if fd == -1 {
+			let err = helpers::last_error();
+			helpers::unwatch_signals([syscalls::SIGCHLD as int]);
+			return Err(err);
+		}
+
+		let queue = event_queue as *mut EventQueueImpl as uint;
+		let mut signal = ~ChildSignal { fd: fd, queue: queue, watchers: ~[], orphans: ~[] };
+		let ptr: *mut ChildSignal = &mut *signal;
+		let mut signals = local_data::pop(CHILD_SIGNALS).unwrap_or(~[]);
+		signals.push((queue, ptr));
+		local_data::set(CHILD_SIGNALS, signals);
+		// The signal is dropped on error, which also removes it from CHILD_SIGNALS
+		if_ok!(event_queue.add_owned_fd(fd, syscalls::EPOLLIN, signal as ~FdOwner:));
+		Ok(ptr)
+	}
+}
+
+impl FdOwner for ChildSignal {
+	fn process(&mut self, event_queue: &mut EventQueueImpl, _epoll_events: u32) -> bool {
+		let mut info = syscalls::signalfd_siginfo::new();
+		let size = mem::size_of::<syscalls::signalfd_siginfo>();
+		loop { // Drain the signalfd
+			let ret = helpers::retry(|| unsafe {
+				libc::read(self.fd,
+				           &mut info as *mut syscalls::signalfd_siginfo as *mut libc::c_void,
+				           size as libc::size_t) as i32
+			});
+			if ret as uint != size { break; }
+		}
+
+		self.orphans.retain(|&pid| !reap(pid));
+
+		// Watchers may remove themselves from the list
+		let watchers = self.watchers.clone();
+		for ptr in watchers.iter() {
+			let cb: *fn(*libc::c_void, &mut EventQueueImpl, u32)
+			        = unsafe { cast::transmute(*ptr) };
+			unsafe { (*cb)(*ptr, event_queue, syscalls::EPOLLIN) };
+		}
+		true
+	}
+}
+
+impl Drop for ChildSignal {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.fd); }
+		helpers::unwatch_signals([syscalls::SIGCHLD as int]);
+		let queue = self.queue;
+		match local_data::pop(CHILD_SIGNALS) {
+			Some(mut signals) => {
+				signals.retain(|&(key, _)| key != queue);
+				local_data::set(CHILD_SIGNALS, signals);
+			},
+			None => {}
+		}
+	}
+}
+
+/// A child with a pidfd whose Process was dropped before it exited
+struct Orphan {
+	pid: libc::pid_t,
+	pidfd: i32
+}
+
+impl FdOwner for Orphan {
+	fn process(&mut self, _event_queue: &mut EventQueueImpl, _epoll_events: u32) -> bool {
+		!reap(self.pid)
+	}
+}
+
+impl Drop for Orphan {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.pidfd); }
+	}
+}
+
+/**
+ * A child process.
+ * Queues a ProcessExitedEvent when the child exited and a
+ * ProcessTimedOutEvent before that if it was killed because of the timeout.
+ * The exit is detected through a pidfd. On kernels without pidfd support
+ * the EventQueue watches SIGCHLD instead, which only works reliably if all
+ * children are watched by a single EventQueue. SIGCHLD is then blocked
+ * for the thread that spawns the first Process, so that must happen before
+ * other threads are spawned. Threads that existed before would still get
+ * the signal and the exit could go unnoticed.
+ */
+pub struct Process {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv pid: libc::pid_t,
+	priv pidfd: i32, // -1 if SIGCHLD is used
+	priv process_group: bool,
+	priv exit_status: Option<ProcessExit>,
+	priv deadline: Option<u64>,
+	priv stdin: Option<~PipeWriter>,
+	priv stdout: Option<~PipeReader>,
+	priv stderr: Option<~PipeReader>,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl events::EventSource for Process {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl Process {
+	/// Starts the program, which is searched in PATH
+	pub fn spawn(program: &str, args: &[~str], options: ProcessOptions, event_queue: &EventQueue) -> IoResult<~Process> {
+		// Everything the child needs is allocated before forking
+		let program_c = program.to_c_str();
+		let mut arg_cs: ~[CString] = ~[program.to_c_str()];
+		for arg in args.iter() {
+			arg_cs.push(arg.to_c_str());
+		}
+		let mut argv: ~[*libc::c_char] = arg_cs.iter().map(|c| c.with_ref(|p| p)).collect();
+		argv.push(ptr::null());
+
+		let env_cs: Option<~[CString]> = options.env.as_ref().map(|env|
+			env.iter().map(|&(ref k, ref v)| format!("{}={}", *k, *v).to_c_str()).collect());
+		let envp: Option<~[*libc::c_char]> = env_cs.as_ref().map(|env| {
+			let mut envp: ~[*libc::c_char] = env.iter().map(|c| c.with_ref(|p| p)).collect();
+			envp.push(ptr::null());
+			envp
+		});
+		let cwd_c: Option<CString> = options.cwd.as_ref().map(|p| p.to_c_str());
+
+		let mut fds: ~[i32] = ~[];
+		let (stdin_child, stdin_parent) = if_ok!(stdio_fds(options.stdin, true));
+		fds.push(stdin_child); fds.push(stdin_parent);
+		let (stdout_child, stdout_parent) = match stdio_fds(options.stdout, false) {
+			Ok(ends) => ends,
+			Err(err) => { close_fds(fds.as_slice()); return Err(err); }
+		};
+		fds.push(stdout_child); fds.push(stdout_parent);
+		let (stderr_child, stderr_parent) = match stdio_fds(options.stderr, false) {
+			Ok(ends) => ends,
+			Err(err) => { close_fds(fds.as_slice()); return Err(err); }
+		};
+		fds.push(stderr_child); fds.push(stderr_parent);
+
+		// Reports a failed exec to the parent. It's closed by a successful exec.
+		let mut error_pipe = [-1i32, -1i32];
+		if unsafe { syscalls::pipe2(error_pipe.as_mut_ptr(), syscalls::O_CLOEXEC) } == -1 {
+			let err = helpers::last_error();
+			close_fds(fds.as_slice());
+			return Err(err);
+		}
+
+		let pid = unsafe { libc::fork() };
+		if pid == -1 {
+			let err = helpers::last_error();
+			close_fds(fds.as_slice());
+			close_fds(error_pipe.as_slice());
+			return Err(err);
+		}
+
+		if pid == 0 { // Child: only async signal safe calls from here on
+			unsafe {
+				let empty_mask = syscalls::sigset_t::new();
+				let mut old_mask = syscalls::sigset_t::new();
+				syscalls::pthread_sigmask(syscalls::SIG_SETMASK, &empty_mask, &mut old_mask);
+
+				if options.new_process_group {
+					syscalls::setpgid(0, 0);
+				}
+				let std_fds = [(stdin_child, 0), (stdout_child, 1), (stderr_child, 2)];
+				let mut ok = true;
+				for &(fd, target) in std_fds.iter() {
+					if fd != -1 && libc::dup2(fd, target) == -1 {
+						ok = false;
+					}
+				}
+				match cwd_c {
+					Some(ref cwd) if ok => {
+						ok = cwd.with_ref(|p| libc::chdir(p)) == 0;
+					},
+					_ => {}
+				}
+				if ok {
+					match envp {
+						Some(ref envp) => {
+							syscalls::execvpe(program_c.with_ref(|p| p), argv.as_ptr(), envp.as_ptr());
+						},
+						None => {
+							libc::execvp(program_c.with_ref(|p| p), argv.as_ptr());
+						}
+					}
+				}
+				let errno = os::errno() as i32;
+				libc::write(error_pipe[1], &errno as *i32 as *libc::c_void, 4);
+				syscalls::_exit(127);
+			}
+		}
+
+		// Parent
+		close_fds(&[stdin_child, stdout_child, stderr_child, error_pipe[1]]);
+		if options.new_process_group {
+			// Also done here so that kill_group works before the child ran
+			unsafe { syscalls::setpgid(pid, pid); }
+		}
+		let mut errno: i32 = 0;
+		let ret = helpers::retry(|| unsafe {
+			libc::read(error_pipe[0], &mut errno as *mut i32 as *mut libc::c_void, 4) as libc::c_int
+		});
+		close_fds(&[error_pipe[0]]);
+		if ret == 4 { // exec failed
+			close_fds(&[stdin_parent, stdout_parent, stderr_parent]);
+			let mut status = 0;
+			unsafe { syscalls::waitpid(pid, &mut status, 0); }
+			return Err(helpers::translate_error(errno, false));
+		}
+
+		let (stdin, stdout, stderr) = match open_streams(stdin_parent, stdout_parent, stderr_parent, event_queue) {
+			Ok(streams) => streams,
+			Err(err) => {
+				unsafe {
+					syscalls::kill(pid, syscalls::SIGKILL);
+					let mut status = 0;
+					syscalls::waitpid(pid, &mut status, 0);
+				}
+				return Err(err);
+			}
+		};
+
+		let mut process = ~Process {
+			process_func: Process::process_epoll_events,
+			pid: pid,
+			pidfd: -1,
+			process_group: options.new_process_group,
+			exit_status: None,
+			deadline: None,
+			stdin: stdin,
+			stdout: stdout,
+			stderr: stderr,
+			event_queue: event_queue._get_impl(),
+			event_source_info: Rc::new(events::EventSourceInfo::new())
+		};
+
+		let callback: *libc::c_void = unsafe { cast::transmute(&process.process_func) };
+		let pidfd = unsafe { syscalls::syscall(syscalls::SYS_PIDFD_OPEN, pid, 0) as i32 };
+		if pidfd != -1 {
+			process.pidfd = pidfd;
+			process.event_queue.borrow().with_mut(|q|
+				q.register_fd(pidfd, syscalls::EPOLLIN, callback)
+			);
+		}
+		else {
+			let res = process.event_queue.borrow().with_mut(|q| ChildSignal::get(q));
+			let signal = if_ok!(res);
+			unsafe { (*signal).watchers.push(callback); }
+			// The child might have exited before SIGCHLD was blocked
+			let event_queue = process.event_queue.clone();
+			event_queue.borrow().with_mut(|q| process.check_exit(q));
+		}
+		process.set_timeout(options.timeout);
+		Ok(process)
+	}
+
+	pub fn pid(&self) -> libc::pid_t {
+		self.pid
+	}
+
+	/// Returns None while the child is running
+	pub fn exit_status(&self) -> Option<ProcessExit> {
+		self.exit_status
+	}
+
+	/// Takes the pipe to the standard input of the child if it's piped
+	pub fn take_stdin(&mut self) -> Option<~PipeWriter> {
+		self.stdin.take()
+	}
+
+	pub fn take_stdout(&mut self) -> Option<~PipeReader> {
+		self.stdout.take()
+	}
+
+	pub fn take_stderr(&mut self) -> Option<~PipeReader> {
+		self.stderr.take()
+	}
+
+	/// Sends a signal to the child
+	pub fn kill(&mut self, signal: int) -> IoResult<()> {
+		self.send_signal(self.pid, signal)
+	}
+
+	/// Sends a signal to all processes in the process group of the child
+	pub fn kill_group(&mut self, signal: int) -> IoResult<()> {
+		if !self.process_group {
+			fail!("The process was not started in a new process group");
+		}
+		self.send_signal(-self.pid, signal)
+	}
+
+	fn send_signal(&mut self, pid: libc::pid_t, signal: int) -> IoResult<()> {
+		// The pid might already be reused after the child was reaped
+		if self.exit_status.is_some() {
+			return Ok(());
+		}
+		if unsafe { syscalls::kill(pid, signal as i32) } == -1 {
+			return Err(helpers::last_error());
+		}
+		Ok(())
+	}
+
+	/**
+	 * Kills the child with SIGKILL if it didn't exit after `timeout`
+	 * milliseconds from now. None removes the timeout.
+	 */
+	pub fn set_timeout(&mut self, timeout: Option<u32>) {
+		let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+		if self.deadline.is_some() {
+			self.event_queue.borrow().with_mut(|q| q.remove_slack_timer(callback));
+			self.deadline = None;
+		}
+		match timeout {
+			Some(timeout) if self.exit_status.is_none() => {
+				self.deadline = Some(helpers::monotonic_time_ns() + timeout as u64 * 1000000);
+				self.event_queue.borrow().with_mut(|q| q.add_slack_timer(callback, timeout, 0, true));
+			},
+			_ => {}
+		}
+	}
+
+	/// Reaps the child if it exited and queues the ProcessExitedEvent
+	fn check_exit(&mut self, event_queue: &mut EventQueueImpl) {
+		if self.exit_status.is_some() { return; }
+		let mut status = 0;
+		let ret = unsafe { syscalls::waitpid(self.pid, &mut status, syscalls::WNOHANG) };
+		if ret != self.pid { return; }
+
+		let exit = translate_status(status);
+		self.exit_status = Some(exit);
+		let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+		if self.pidfd != -1 {
+			event_queue.unregister_fd(self.pidfd);
+			unsafe { libc::close(self.pidfd); }
+			self.pidfd = -1;
+		}
+		else {
+			match ChildSignal::find(event_queue) {
+				Some(signal) => unsafe { (*signal).watchers.retain(|cb| *cb != callback); },
+				None => {}
+			}
+		}
+		if self.deadline.is_some() {
+			event_queue.remove_slack_timer(callback);
+			self.deadline = None;
+		}
+		event_queue.push_back_event(events::Event {
+			event_type: events::ProcessExitedEvent(exit),
+			is_valid: true,
+			source_info: self.event_source_info.clone()
+		});
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, _epoll_events: u32) {
+		unsafe {
+			let process: *mut Process = func_ptr as *mut Process;
+			// The same callback is used for the pidfd, SIGCHLD and the timeout
+			(*process).check_exit(event_queue);
+			match (*process).deadline {
+				Some(deadline) if helpers::monotonic_time_ns() >= deadline => {
+					(*process).deadline = None;
+					let pid = if (*process).process_group { -(*process).pid } else { (*process).pid };
+					syscalls::kill(pid, syscalls::SIGKILL);
+					event_queue.push_back_event(events::Event {
+						event_type: events::ProcessTimedOutEvent,
+						is_valid: true,
+						source_info: (*process).event_source_info.clone()
+					});
+				},
+				_ => {}
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+/**
+ * Closes the standard input but doesn't wait for the child. A child that
+ * is still running is neither killed nor waited for. The EventQueue reaps
+ * it without blocking once it exits, as long as the EventQueue lives.
+ */
+#[unsafe_destructor]
+impl Drop for Process {
+	fn drop(&mut self) {
+		self.stdin = None;
+		self.set_timeout(None);
+		if self.exit_status.is_none() {
+			let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+			let pid = self.pid;
+			let pidfd = self.pidfd;
+			self.pidfd = -1;
+			self.event_queue.borrow().with_mut(|q| {
+				if pidfd != -1 {
+					q.unregister_fd(pidfd);
+					// Fails only without memory for epoll, then the child stays a zombie
+					let _ = q.add_owned_fd(pidfd, syscalls::EPOLLIN, ~Orphan { pid: pid, pidfd: pidfd } as ~FdOwner:);
+				}
+				else {
+					match ChildSignal::find(q) {
+						Some(signal) => unsafe {
+							(*signal).watchers.retain(|cb| *cb != callback);
+							// The child might have exited in the meantime
+							if !reap(pid) {
+								(*signal).orphans.push(pid);
+							}
+						},
+						None => {}
+					}
+				}
+			});
+		}
+		self.remove_pending_events();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::process::ExitStatus;
+	use eventqueue::EventQueue;
+	use events;
+	use syscalls;
+	use super::{Process, ProcessOptions, NullStdio};
+
+	fn options() -> ProcessOptions {
+		let mut options = ProcessOptions::new();
+		options.stdin = NullStdio;
+		options.stdout = NullStdio;
+		options.stderr = NullStdio;
+		options
+	}
+
+	#[test]
+	fn exit_status_is_reported() {
+		let mut queue = EventQueue::new();
+		let process = Process::spawn("sh", [~"-c", ~"exit 3"], options(), &queue).unwrap();
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::ProcessExitedEvent(ExitStatus(3)) => {},
+			_ => fail!("Expected a ProcessExitedEvent")
+		}
+		assert!(event.originates_from(process));
+		assert_eq!(process.exit_status(), Some(ExitStatus(3)));
+	}
+
+	#[test]
+	fn dropped_process_is_reaped_by_the_queue() {
+		let queue = EventQueue::new();
+		let process = Process::spawn("sleep", [~"0.2"], options(), &queue).unwrap();
+		let pid = process.pid();
+		// Doesn't block although the child is still running
+		drop(process);
+		// Wakes up when the child exits
+		queue._get_impl().borrow().with_mut(|q| q.poll_events()).unwrap();
+		let mut status = 0;
+		assert_eq!(unsafe { syscalls::waitpid(pid, &mut status, syscalls::WNOHANG) }, -1);
+	}
+}
@@ -0,0 +1,509 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::io;
+use std::libc;
+use std::os;
+use std::io::IoError;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::events;
+use super::IoResult;
+use super::eventqueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::cron::CronSchedule;
+use super::tzfile::{ZoneInfo, div_floor, days_from_civil, civil_from_days, weekday_from_days};
+use super::syscalls;
+use super::helpers;
+
+static MINUTES_PER_DAY: i64 = 1440;
+
+// Expressions that only match on february 29th can skip 8 years, e.g. from 2096 to 2104
+static SEARCH_YEARS: i64 = 8;
+
+/// The time zone in which a cron expression is evaluated
+#[deriving(Clone)]
+pub enum TimeZone {
+	LocalTime,
+	Utc,
+	/// A zone of the tz database like "Europe/Berlin" or a POSIX TZ string
+	NamedZone(~str)
+}
+
+fn invalid_input(desc: &'static str, detail: ~str) -> IoError {
+	IoError {
+		kind: io::InvalidInput,
+		desc: desc,
+		detail: Some(detail)
+	}
+}
+
+fn never_matches() -> IoError {
+	invalid_input("Invalid cron expression", ~"The expression never matches")
+}
+
+/**
+ * A timer that fires according to a cron expression.
+ * The fire times are calculated in local time, in UTC or in a named time
+ * zone and an absolute CLOCK_REALTIME timerfd is armed for the next one.
+ * When the system clock is stepped the next fire time is recalculated.
+ * Like in cron, wall clock times that are skipped by a DST transition fire
+ * at the first instant after the transition and wall clock times that
+ * occur twice only fire once.
+ * Each expiration queues a TimerEvent.
+ */
+pub struct ScheduledTimer {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv fd: i32,
+	priv schedule: CronSchedule,
+	priv zone: TimeZone,
+	/// The rules of a NamedZone
+	priv zone_info: Option<ZoneInfo>,
+	priv is_active: bool,
+	priv epoll_registered: bool,
+	priv next_fire_time: i64,
+	/// The wall clock minute that fires at next_fire_time
+	priv next_fire_wall_time: i64,
+	priv last_fired_wall_time: i64,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl ScheduledTimer {
+	/**
+	 * Creates an inactive timer for the expression.
+	 * Like with CRON_TZ in a crontab, the expression can be prefixed with
+	 * `CRON_TZ=<zone> ` to evaluate it in a named time zone.
+	 * Fails if the expression is invalid or never matches.
+	 */
+	pub fn create(expression: &str, event_queue: &eventqueue::EventQueue) -> IoResult<~ScheduledTimer> {
+		let mut expression = expression.trim();
+		let mut zone = LocalTime;
+		let mut zone_info = None;
+		if expression.starts_with("CRON_TZ=") {
+			let end = expression.find(|c: char| c.is_whitespace()).unwrap_or(expression.len());
+			let name = expression.slice(8, end);
+			zone_info = Some(if_ok!(ZoneInfo::load(name)));
+			zone = NamedZone(name.to_owned());
+			expression = expression.slice_from(end);
+		}
+		let schedule = if_ok!(CronSchedule::parse(expression));
+		let tfd = unsafe {
+			syscalls::timerfd_create(libc::CLOCK_REALTIME, 0)
+		};
+		if tfd == -1 {
+			return Err(helpers::last_error());
+		}
+		// localtime_r doesn't have to initialize the local time zone itself
+		unsafe { syscalls::tzset(); }
+		let timer = ~ScheduledTimer{
+			fd: tfd,
+			schedule: schedule,
+			zone: zone,
+			zone_info: zone_info,
+			is_active: false,
+			epoll_registered: false,
+			next_fire_time: 0,
+			next_fire_wall_time: 0,
+			last_fired_wall_time: -1,
+			event_queue: event_queue._get_impl(),
+			process_func: ScheduledTimer::process_epoll_events,
+			event_source_info: Rc::new(events::EventSourceInfo::new())
+		};
+		if timer.next_after(now()).is_none() {
+			return Err(never_matches());
+		}
+		Ok(timer)
+	}
+
+	/**
+	 * Evaluates the expression in UTC instead of local time.
+	 * Fails and stops an active timer if the expression doesn't match anymore.
+	 */
+	pub fn set_utc(&mut self, utc: bool) -> IoResult<()> {
+		self.set_zone(if utc { Utc } else { LocalTime }, None)
+	}
+
+	pub fn is_utc(&self) -> bool {
+		match self.zone {
+			Utc => true,
+			_ => false
+		}
+	}
+
+	/**
+	 * Evaluates the expression in the zone. Fails for unknown named zones
+	 * and stops an active timer if the expression doesn't match anymore.
+	 */
+	pub fn set_time_zone(&mut self, zone: TimeZone) -> IoResult<()> {
+		let zone_info = match zone {
+			NamedZone(ref name) => Some(if_ok!(ZoneInfo::load(name.as_slice()))),
+			_ => None
+		};
+		self.set_zone(zone, zone_info)
+	}
+
+	pub fn get_time_zone(&self) -> TimeZone {
+		self.zone.clone()
+	}
+
+	fn set_zone(&mut self, zone: TimeZone, zone_info: Option<ZoneInfo>) -> IoResult<()> {
+		self.zone = zone;
+		self.zone_info = zone_info;
+		// Wall clock times that fired are only comparable within a zone
+		self.last_fired_wall_time = -1;
+		if self.is_active && !self.arm() {
+			return Err(never_matches());
+		}
+		Ok(())
+	}
+
+	pub fn is_active(&self) -> bool {
+		self.is_active
+	}
+
+	/// Returns the next fire time in seconds since the epoch if the timer is active
+	pub fn next_fire_time(&self) -> Option<i64> {
+		if self.is_active { Some(self.next_fire_time) } else { None }
+	}
+
+	/// Fails if the expression doesn't match anymore
+	pub fn start(&mut self) -> IoResult<()> {
+		if self.is_active { return Ok(()); }
+
+		self.is_active = true;
+		if !self.arm() {
+			return Err(never_matches());
+		}
+
+		let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+		if !self.epoll_registered {
+			self.event_queue.borrow().with_mut(|q|
+				q.register_fd(self.fd, syscalls::EPOLLIN, callback)
+			);
+			self.epoll_registered = true;
+		}
+		Ok(())
+	}
+
+	pub fn stop(&mut self) {
+		if !self.is_active { return; }
+
+		self.disarm();
+		if self.epoll_registered {
+			self.event_queue.borrow().with_mut(
+				|q|q.unregister_fd(self.fd)
+			);
+			self.epoll_registered = false;
+		}
+		self.is_active = false;
+		self.remove_pending_events();
+	}
+
+	fn disarm(&mut self) {
+		let new_value = syscalls::itimerspec::new(); // init to 0
+		let ret = unsafe {
+			syscalls::timerfd_settime(self.fd, 0, &new_value, 0 as *syscalls::itimerspec)
+		};
+		if ret != 0 {
+			fail!("Error on stopping timer {0}", helpers::last_error().desc);
+		}
+	}
+
+	/**
+	 * Calculates the next fire time starting from now and arms the timerfd.
+	 * Deactivates the timer and returns false if the expression never matches.
+	 */
+	fn arm(&mut self) -> bool {
+		match self.next_after(now()) {
+			None => {
+				self.disarm();
+				self.is_active = false;
+				return false;
+			},
+			Some((time, wall_time)) => {
+				self.next_fire_time = time;
+				self.next_fire_wall_time = wall_time;
+			}
+		}
+
+		let mut new_value = syscalls::itimerspec::new();
+		new_value.it_value.tv_sec = self.next_fire_time as libc::time_t;
+		let ret = unsafe {
+			syscalls::timerfd_settime(self.fd,
+				syscalls::TFD_TIMER_ABSTIME | syscalls::TFD_TIMER_CANCEL_ON_SET,
+				&new_value, 0 as *syscalls::itimerspec)
+		};
+		if ret != 0 {
+			fail!("Error on starting timer {0}", helpers::last_error().desc);
+		}
+		true
+	}
+
+	/// Remembers the wall clock minute of the current fire time
+	fn record_fired(&mut self) {
+		self.last_fired_wall_time = self.next_fire_wall_time;
+	}
+
+	/// Returns the offset of the zone in seconds east of UTC at `time`
+	fn utc_offset(&self, time: i64) -> i64 {
+		match self.zone {
+			Utc => 0,
+			LocalTime => {
+				let t = time as libc::time_t;
+				let mut tm = syscalls::tm::new();
+				unsafe { syscalls::localtime_r(&t, &mut tm); }
+				tm.tm_gmtoff as i64
+			},
+			NamedZone(_) => self.zone_info.get_ref().utc_offset(time)
+		}
+	}
+
+	/**
+	 * Returns the first instant after `after` at which the wall clock shows
+	 * the minute. A minute that is skipped by a transition maps to the
+	 * transition.
+	 */
+	fn instant_of(&self, wall_minute: i64, after: i64) -> Option<i64> {
+		let wall = wall_minute * 60;
+		// Assumes at most one transition within a day around the wall clock time
+		let offset_before = self.utc_offset(wall - 86400);
+		let offset_after = self.utc_offset(wall + 86400);
+		let (high, low) = if offset_before > offset_after {
+			(offset_before, offset_after)
+		}
+		else {
+			(offset_after, offset_before)
+		};
+
+		// The larger offset gives the earlier instant of a repeated minute
+		let mut exists = false;
+		for &offset in [high, low].iter() {
+			let time = wall - offset;
+			if self.utc_offset(time) == offset {
+				if time > after {
+					return Some(time);
+				}
+				exists = true;
+			}
+		}
+		if exists {
+			return None;
+		}
+
+		// The minute is skipped. Search the transition between the two candidates.
+		let mut early = wall - high;
+		let mut late = wall - low;
+		let early_offset = self.utc_offset(early);
+		while late - early > 1 {
+			let mid = early + (late - early) / 2;
+			if self.utc_offset(mid) == early_offset { early = mid; } else { late = mid; }
+		}
+		if late > after { Some(late) } else { None }
+	}
+
+	/**
+	 * Returns the first matching time after `after` and its wall clock minute.
+	 * Walks the wall clock time field by field and skips whole months, days
+	 * and hours that don't match.
+	 */
+	fn next_after(&self, after: i64) -> Option<(i64, i64)> {
+		let start = div_floor(after, 60) * 60 + 60;
+		let mut wall = div_floor(start + self.utc_offset(start), 60);
+		if wall <= self.last_fired_wall_time {
+			wall = self.last_fired_wall_time + 1;
+		}
+		let (start_year, _, _) = civil_from_days(div_floor(wall, MINUTES_PER_DAY));
+
+		loop {
+			let days = div_floor(wall, MINUTES_PER_DAY);
+			let (year, month, day) = civil_from_days(days);
+			if year > start_year + SEARCH_YEARS {
+				return None;
+			}
+			let minute_of_day = wall - days * MINUTES_PER_DAY;
+			let hour = minute_of_day / 60;
+			let minute = minute_of_day % 60;
+
+			if !self.schedule.matches_month(month as uint) {
+				wall = if month == 12 {
+					days_from_civil(year + 1, 1, 1)
+				}
+				else {
+					days_from_civil(year, month + 1, 1)
+				} * MINUTES_PER_DAY;
+			}
+			else if !self.schedule.matches_day(day as uint, weekday_from_days(days) as uint) {
+				wall = (days + 1) * MINUTES_PER_DAY;
+			}
+			else if !self.schedule.matches_hour(hour as uint) {
+				wall = days * MINUTES_PER_DAY + (hour + 1) * 60;
+			}
+			else if !self.schedule.matches_minute(minute as uint) {
+				wall += 1;
+			}
+			else {
+				match self.instant_of(wall, after) {
+					Some(time) => return Some((time, wall)),
+					None => wall += 1
+				}
+			}
+		}
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let timer: *mut ScheduledTimer = func_ptr as *mut ScheduledTimer;
+
+			if epoll_events & syscalls::EPOLLIN != 0 {
+				let buffer = [0, ..8];
+
+				let ret = helpers::retry(||
+					libc::read((*timer).fd,
+						       buffer.as_ptr() as *mut libc::c_void,
+						       buffer.len() as libc::size_t) as i32
+				);
+
+				if ret == 8 {
+					(*timer).record_fired();
+					let e = events::Event {
+						event_type: events::TimerEvent,
+						is_valid: true,
+						source_info: (*timer).event_source_info.clone()
+					};
+					event_queue.push_back_event(e);
+					(*timer).arm();
+				}
+				else if ret == -1 && os::errno() as i32 == syscalls::ECANCELED {
+					// The clock was stepped. Calculate the next time from the new time.
+					(*timer).arm();
+				}
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+fn now() -> i64 {
+	let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+	unsafe { syscalls::clock_gettime(libc::CLOCK_REALTIME, &mut now); }
+	now.tv_sec as i64
+}
+
+#[unsafe_destructor]
+impl Drop for ScheduledTimer {
+	fn drop(&mut self) {
+		self.remove_pending_events();
+		unsafe { libc::close(self.fd); }
+	}
+}
+
+impl events::EventSource for ScheduledTimer {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use super::{ScheduledTimer, NamedZone};
+
+	// The rule of Europe/Berlin, so the tests don't depend on the installed tz database
+	static BERLIN: &'static str = "CET-1CEST,M3.5.0,M10.5.0/3";
+	// 2014-03-29 12:00 in Berlin, the day before DST starts
+	static BEFORE_SPRING_FORWARD: i64 = 1396090800;
+	// 2014-10-25 12:00 in Berlin, the day before DST ends
+	static BEFORE_FALL_BACK: i64 = 1414231200;
+
+	fn fire(timer: &mut ScheduledTimer, after: i64) -> i64 {
+		let (next, wall_time) = timer.next_after(after).unwrap();
+		timer.next_fire_time = next;
+		timer.next_fire_wall_time = wall_time;
+		timer.record_fired();
+		next
+	}
+
+	#[test]
+	fn skipped_time_fires_after_transition() {
+		let queue = EventQueue::new();
+		let expression = format!("CRON_TZ={} 30 2 * * *", BERLIN);
+		let mut timer = ScheduledTimer::create(expression.as_slice(), &queue).unwrap();
+		// 03:00 CEST on the 30th, right after the skipped hour
+		let first = fire(&mut *timer, BEFORE_SPRING_FORWARD);
+		assert_eq!(first, 1396141200);
+		// 02:30 CEST on the 31st
+		assert_eq!(fire(&mut *timer, first), 1396225800);
+	}
+
+	#[test]
+	fn repeated_time_fires_once() {
+		let queue = EventQueue::new();
+		let expression = format!("CRON_TZ={} 30 2 * * *", BERLIN);
+		let mut timer = ScheduledTimer::create(expression.as_slice(), &queue).unwrap();
+		// The first 02:30 on the 26th is still in CEST
+		let first = fire(&mut *timer, BEFORE_FALL_BACK);
+		assert_eq!(first, 1414283400);
+		// The second 02:30 in CET is skipped, the next one is on the 27th
+		assert_eq!(fire(&mut *timer, first), 1414373400);
+	}
+
+	#[test]
+	fn repeated_time_fires_when_started_in_between() {
+		let queue = EventQueue::new();
+		let expression = format!("CRON_TZ={} 30 2 * * *", BERLIN);
+		let timer = ScheduledTimer::create(expression.as_slice(), &queue).unwrap();
+		// 02:10 CET, after the first 02:30 passed
+		assert_eq!(timer.next_after(1414285800), Some((1414287000, 23571510)));
+	}
+
+	#[test]
+	fn utc_schedule() {
+		let queue = EventQueue::new();
+		let mut timer = ScheduledTimer::create("30 2 * * *", &queue).unwrap();
+		assert!(timer.set_utc(true).is_ok());
+		assert!(timer.is_utc());
+		// 2014-03-29 12:00 UTC to 2014-03-30 02:30 UTC
+		assert_eq!(timer.next_after(1396094400), Some((1396146600, 23269110)));
+	}
+
+	#[test]
+	fn leap_day_is_found() {
+		let queue = EventQueue::new();
+		let mut timer = ScheduledTimer::create("0 0 29 2 *", &queue).unwrap();
+		assert!(timer.set_utc(true).is_ok());
+		// 2014-03-29 12:00 UTC to 2016-02-29 00:00 UTC
+		assert_eq!(timer.next_after(1396094400), Some((1456704000, 24278400)));
+	}
+
+	#[test]
+	fn never_matching_expression_is_rejected() {
+		let queue = EventQueue::new();
+		assert!(ScheduledTimer::create("0 0 30 2 *", &queue).is_err());
+	}
+
+	#[test]
+	fn unknown_zone_is_rejected() {
+		let queue = EventQueue::new();
+		assert!(ScheduledTimer::create("CRON_TZ=Nowhere/Nothing 0 0 * * *", &queue).is_err());
+		assert!(ScheduledTimer::create("CRON_TZ=../../etc/passwd 0 0 * * *", &queue).is_err());
+		let mut timer = ScheduledTimer::create("0 0 * * *", &queue).unwrap();
+		assert!(timer.set_time_zone(NamedZone(~"Nowhere/Nothing")).is_err());
+	}
+}
@@ -0,0 +1,545 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::cmp;
+use std::io;
+use std::libc;
+use std::ptr;
+use std::io::IoError;
+use std::c_str::ToCStr;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomics::{AtomicBool, AtomicUint, SeqCst};
+
+use super::IoResult;
+use super::events;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::syscalls;
+use super::helpers;
+
+/**
+ * Lives at the start of the shared mapping, followed by the data.
+ * The positions only grow and are reduced modulo the capacity when used.
+ * They are on separate cache lines because each is written by another process.
+ * The other process can write anything into the header, so both sides
+ * validate the positions before using them.
+ */
+struct RingHeader {
+	write_pos: AtomicUint,
+	_pad1: [u8, ..56],
+	read_pos: AtomicUint,
+	_pad2: [u8, ..56],
+	consumer_notified: AtomicBool,
+	producer_closed: AtomicBool,
+	consumer_closed: AtomicBool
+}
+
+static HEADER_SIZE: uint = 256;
+
+/**
+ * The file descriptors of a ring: the shared memory and the eventfd that
+ * wakes the consumer. Both are close-on-exec and closed when the handle is
+ * dropped. To share a ring with a forked process clone the handle before
+ * forking. An exec'd process must get the descriptors passed explicitly.
+ */
+pub struct ShmRingHandle {
+	priv memfd: i32,
+	priv eventfd: i32
+}
+
+impl ShmRingHandle {
+	pub unsafe fn from_fds(memfd: i32, eventfd: i32) -> ShmRingHandle {
+		ShmRingHandle{memfd: memfd, eventfd: eventfd}
+	}
+
+	pub fn memfd(&self) -> i32 {
+		self.memfd
+	}
+
+	pub fn eventfd(&self) -> i32 {
+		self.eventfd
+	}
+
+	/// Duplicates both file descriptors
+	pub fn try_clone(&self) -> IoResult<ShmRingHandle> {
+		unsafe {
+			let memfd = libc::dup(self.memfd);
+			if memfd == -1 {
+				return Err(helpers::last_error());
+			}
+			let eventfd = libc::dup(self.eventfd);
+			if eventfd == -1 {
+				let err = helpers::last_error();
+				libc::close(memfd);
+				return Err(err);
+			}
+			Ok(ShmRingHandle{memfd: memfd, eventfd: eventfd})
+		}
+	}
+}
+
+impl Drop for ShmRingHandle {
+	fn drop(&mut self) {
+		unsafe {
+			if self.memfd != -1 { libc::close(self.memfd); }
+			if self.eventfd != -1 { libc::close(self.eventfd); }
+		}
+	}
+}
+
+/**
+ * A single-producer single-consumer byte ring in memory that is shared
+ * between processes. The consumer is woken through an eventfd. The producer
+ * only writes to the eventfd if the consumer has seen the previous wakeup.
+ */
+pub struct ShmRing;
+
+impl ShmRing {
+	/// Creates the shared memory for a ring. The capacity must be a power of two.
+	pub fn create(capacity: uint) -> IoResult<ShmRingHandle> {
+		if capacity == 0 || capacity & (capacity - 1) != 0 {
+			return Err(IoError{
+				kind: io::InvalidInput,
+				desc: "The capacity must be a power of two",
+				detail: Some(format!("{}", capacity))
+			});
+		}
+		unsafe {
+			let memfd = "revbio-shmring".with_c_str(|name|
+				syscalls::syscall(syscalls::SYS_MEMFD_CREATE, name, syscalls::MFD_CLOEXEC) as i32
+			);
+			if memfd == -1 {
+				return Err(helpers::last_error());
+			}
+			// The new memory is zeroed, which is a valid empty header
+			if syscalls::ftruncate(memfd, (HEADER_SIZE + capacity) as libc::off_t) == -1 {
+				let err = helpers::last_error();
+				libc::close(memfd);
+				return Err(err);
+			}
+			let eventfd = syscalls::eventfd(0, syscalls::EFD_CLOEXEC);
+			if eventfd == -1 {
+				let err = helpers::last_error();
+				libc::close(memfd);
+				return Err(err);
+			}
+			Ok(ShmRingHandle{memfd: memfd, eventfd: eventfd})
+		}
+	}
+}
+
+/// The mapping of the ring in the own address space
+struct Mapping {
+	addr: *mut libc::c_void,
+	size: uint,
+	capacity: uint
+}
+
+impl Mapping {
+	fn map(memfd: i32) -> IoResult<Mapping> {
+		unsafe {
+			let size = libc::lseek(memfd, 0, libc::SEEK_END);
+			if size == -1 {
+				return Err(helpers::last_error());
+			}
+			let size = size as uint;
+			let capacity = if size > HEADER_SIZE { size - HEADER_SIZE } else { 0 };
+			if capacity == 0 || capacity & (capacity - 1) != 0 {
+				return Err(IoError{
+					kind: io::InvalidInput,
+					desc: "Not a shared memory ring",
+					detail: None
+				});
+			}
+			let addr = libc::mmap(ptr::null(), size as libc::size_t,
+			                      libc::PROT_READ | libc::PROT_WRITE,
+			                      libc::MAP_SHARED, memfd, 0);
+			if addr as *libc::c_void == libc::MAP_FAILED {
+				return Err(helpers::last_error());
+			}
+			Ok(Mapping{addr: addr, size: size, capacity: capacity})
+		}
+	}
+
+	fn header(&self) -> *mut RingHeader {
+		self.addr as *mut RingHeader
+	}
+
+	fn buffer(&self) -> *mut u8 {
+		unsafe { (self.addr as *mut u8).offset(HEADER_SIZE as int) }
+	}
+
+	/**
+	 * Loads the write and read position once each. Returns None if they
+	 * don't describe between 0 and `capacity` bytes of data, which is a
+	 * protocol error of the other side.
+	 */
+	fn positions(&self) -> Option<(uint, uint)> {
+		let header = self.header();
+		let (write_pos, read_pos) = unsafe {
+			((*header).write_pos.load(SeqCst), (*header).read_pos.load(SeqCst))
+		};
+		// Wraps if the read position is ahead of the write position
+		if write_pos - read_pos > self.capacity { None }
+		else { Some((write_pos, read_pos)) }
+	}
+}
+
+fn protocol_error() -> IoError {
+	IoError{
+		kind: io::OtherIoError,
+		desc: "Corrupted shared memory ring positions",
+		detail: None
+	}
+}
+
+impl Drop for Mapping {
+	fn drop(&mut self) {
+		unsafe { libc::munmap(self.addr as *libc::c_void, self.size as libc::size_t); }
+	}
+}
+
+/// The writing side of a shared memory ring
+pub struct ShmProducer {
+	priv handle: ShmRingHandle,
+	priv mapping: Mapping,
+	priv broken: bool
+}
+
+impl ShmProducer {
+	pub fn from_handle(handle: ShmRingHandle) -> IoResult<ShmProducer> {
+		Mapping::map(handle.memfd).map(|mapping|
+			ShmProducer{handle: handle, mapping: mapping, broken: false})
+	}
+
+	/**
+	 * Copies as many bytes as fit into the ring and returns their number.
+	 * Never blocks. The consumer is woken if it waits for data.
+	 * If the consumer corrupted the ring positions the ring is closed and
+	 * nothing is written.
+	 */
+	pub fn write(&mut self, buf: &[u8]) -> uint {
+		let header = self.mapping.header();
+		let capacity = self.mapping.capacity;
+		let (write_pos, read_pos) = match self.positions() {
+			Some(positions) => positions,
+			None => return 0
+		};
+		unsafe {
+			let count = cmp::min(buf.len(), capacity - (write_pos - read_pos));
+			if count == 0 { return 0; }
+
+			let offset = write_pos & (capacity - 1);
+			let first = cmp::min(count, capacity - offset);
+			let buffer = self.mapping.buffer();
+			ptr::copy_nonoverlapping_memory(buffer.offset(offset as int), buf.as_ptr(), first);
+			if first < count {
+				ptr::copy_nonoverlapping_memory(buffer, buf.as_ptr().offset(first as int), count - first);
+			}
+			(*header).write_pos.store(write_pos + count, SeqCst);
+
+			if !(*header).consumer_notified.swap(true, SeqCst) {
+				if helpers::signal_eventfd(self.handle.eventfd) == -1 {
+					fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
+				}
+			}
+			count
+		}
+	}
+
+	/// Returns the number of bytes that can be written without overwriting unread data
+	pub fn free_space(&mut self) -> uint {
+		match self.positions() {
+			Some((write_pos, read_pos)) => self.mapping.capacity - (write_pos - read_pos),
+			None => 0
+		}
+	}
+
+	/// Returns true if the consumer was dropped or the ring was closed after a protocol error
+	pub fn is_closed(&self) -> bool {
+		self.broken || unsafe { (*self.mapping.header()).consumer_closed.load(SeqCst) }
+	}
+
+	/// Validates the positions and closes the ring if they are corrupted
+	fn positions(&mut self) -> Option<(uint, uint)> {
+		if self.broken { return None; }
+		let ret = self.mapping.positions();
+		if ret.is_none() {
+			self.broken = true;
+			unsafe { self.close(); }
+		}
+		ret
+	}
+
+	/// Tells the consumer that no more data follows
+	unsafe fn close(&self) {
+		(*self.mapping.header()).producer_closed.store(true, SeqCst);
+		(*self.mapping.header()).consumer_notified.store(true, SeqCst);
+		helpers::signal_eventfd(self.handle.eventfd);
+	}
+}
+
+impl Drop for ShmProducer {
+	fn drop(&mut self) {
+		if !self.broken {
+			unsafe { self.close(); }
+		}
+	}
+}
+
+/**
+ * The reading side of a shared memory ring.
+ * Queues a DataAvailableEvent with the number of readable bytes when the
+ * producer wrote new data, and a StreamClosedEvent when the producer was
+ * dropped. Data that is still in the ring can be read after the
+ * StreamClosedEvent. If the producer corrupted the ring positions an
+ * IoErrorEvent and a StreamClosedEvent are queued and nothing more is read.
+ */
+pub struct ShmConsumer {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv handle: ShmRingHandle,
+	priv mapping: Mapping,
+	priv closed: bool,
+	priv broken: bool,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl events::EventSource for ShmConsumer {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl ShmConsumer {
+	pub fn from_handle(handle: ShmRingHandle, event_queue: &EventQueue) -> IoResult<~ShmConsumer> {
+		let mapping = match Mapping::map(handle.memfd) {
+			Ok(mapping) => mapping,
+			Err(err) => return Err(err)
+		};
+		syscalls::set_fd_blocking(handle.eventfd, false);
+		let consumer = ~ShmConsumer{
+			process_func: ShmConsumer::process_epoll_events,
+			handle: handle,
+			mapping: mapping,
+			closed: false,
+			broken: false,
+			event_queue: event_queue._get_impl(),
+			event_source_info: Rc::new(events::EventSourceInfo::new())
+		};
+
+		let callback: *libc::c_void = unsafe { cast::transmute(&consumer.process_func) };
+		consumer.event_queue.borrow().with_mut(|q|
+			q.register_fd(consumer.handle.eventfd, syscalls::EPOLLIN, callback)
+		);
+		// Data might have been written before the consumer existed
+		if helpers::signal_eventfd(consumer.handle.eventfd) == -1 {
+			return Err(helpers::last_error());
+		}
+		Ok(consumer)
+	}
+
+	/// Returns the number of bytes that can be read
+	pub fn available(&mut self) -> uint {
+		match self.positions() {
+			Some((write_pos, read_pos)) => write_pos - read_pos,
+			None => 0
+		}
+	}
+
+	/// Copies up to `buf.len()` bytes out of the ring and returns their number
+	pub fn read(&mut self, buf: &mut [u8]) -> uint {
+		let header = self.mapping.header();
+		let capacity = self.mapping.capacity;
+		let (write_pos, read_pos) = match self.positions() {
+			Some(positions) => positions,
+			None => return 0
+		};
+		unsafe {
+			let count = cmp::min(buf.len(), write_pos - read_pos);
+			if count == 0 { return 0; }
+
+			let offset = read_pos & (capacity - 1);
+			let first = cmp::min(count, capacity - offset);
+			let buffer = self.mapping.buffer();
+			ptr::copy_nonoverlapping_memory(buf.as_mut_ptr(), buffer.offset(offset as int) as *u8, first);
+			if first < count {
+				ptr::copy_nonoverlapping_memory(buf.as_mut_ptr().offset(first as int), buffer as *u8, count - first);
+			}
+			(*header).read_pos.store(read_pos + count, SeqCst);
+			count
+		}
+	}
+
+	/// Validates the positions and closes the ring if they are corrupted
+	fn positions(&mut self) -> Option<(uint, uint)> {
+		if self.broken { return None; }
+		let ret = self.mapping.positions();
+		if ret.is_none() {
+			let event_queue = self.event_queue.clone();
+			event_queue.borrow().with_mut(|q| self.close_broken(q));
+		}
+		ret
+	}
+
+	/// Closes the ring after a protocol error of the producer
+	fn close_broken(&mut self, event_queue: &mut EventQueueImpl) {
+		self.broken = true;
+		event_queue.push_back_event(events::Event {
+			event_type: events::IoErrorEvent(protocol_error()),
+			is_valid: true,
+			source_info: self.event_source_info.clone()
+		});
+		if !self.closed {
+			self.closed = true;
+			event_queue.unregister_fd(self.handle.eventfd);
+			event_queue.push_back_event(events::Event {
+				event_type: events::StreamClosedEvent,
+				is_valid: true,
+				source_info: self.event_source_info.clone()
+			});
+		}
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let consumer: *mut ShmConsumer = func_ptr as *mut ShmConsumer;
+			if (*consumer).closed || epoll_events & syscalls::EPOLLIN == 0 { return; }
+
+			let buffer = [0, ..8];
+			let ret = helpers::retry(||
+				libc::read((*consumer).handle.eventfd,
+				           buffer.as_ptr() as *mut libc::c_void,
+				           buffer.len() as libc::size_t) as i32
+			);
+			if ret != 8 { return; }
+
+			let header = (*consumer).mapping.header();
+			// Reset the flag before looking at the ring so that no data can be missed
+			(*header).consumer_notified.store(false, SeqCst);
+			let closed = (*header).producer_closed.load(SeqCst);
+			let available = match (*consumer).mapping.positions() {
+				Some((write_pos, read_pos)) => write_pos - read_pos,
+				None => {
+					(*consumer).close_broken(event_queue);
+					return;
+				}
+			};
+			if available > 0 {
+				event_queue.push_back_event(events::Event {
+					event_type: events::DataAvailableEvent(available),
+					is_valid: true,
+					source_info: (*consumer).event_source_info.clone()
+				});
+			}
+			if closed {
+				(*consumer).closed = true;
+				event_queue.unregister_fd((*consumer).handle.eventfd);
+				event_queue.push_back_event(events::Event {
+					event_type: events::StreamClosedEvent,
+					is_valid: true,
+					source_info: (*consumer).event_source_info.clone()
+				});
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl Drop for ShmConsumer {
+	fn drop(&mut self) {
+		unsafe { (*self.mapping.header()).consumer_closed.store(true, SeqCst); }
+		if !self.closed {
+			let fd = self.handle.eventfd;
+			self.event_queue.borrow().with_mut(|q| q.unregister_fd(fd));
+		}
+		self.remove_pending_events();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::atomics::SeqCst;
+	use eventqueue::EventQueue;
+	use events;
+	use super::{ShmRing, ShmProducer, ShmConsumer};
+
+	fn pair(capacity: uint, queue: &EventQueue) -> (ShmProducer, ~ShmConsumer) {
+		let handle = ShmRing::create(capacity).unwrap();
+		let producer = ShmProducer::from_handle(handle.try_clone().unwrap()).unwrap();
+		let consumer = ShmConsumer::from_handle(handle, queue).unwrap();
+		(producer, consumer)
+	}
+
+	#[test]
+	fn capacity_must_be_power_of_two() {
+		assert!(ShmRing::create(0).is_err());
+		assert!(ShmRing::create(100).is_err());
+	}
+
+	#[test]
+	fn data_wraps_around_the_end() {
+		let queue = EventQueue::new();
+		let (mut producer, mut consumer) = pair(8, &queue);
+		let mut buf = [0u8, ..8];
+		assert_eq!(producer.write([1, 2, 3, 4, 5, 6]), 6);
+		assert_eq!(consumer.read(buf.mut_slice_to(6)), 6);
+		assert_eq!(producer.write([7, 8, 9, 10, 11, 12, 13, 14, 15]), 8);
+		assert_eq!(producer.free_space(), 0);
+		assert_eq!(consumer.read(buf), 8);
+		assert_eq!(buf.to_owned(), ~[7u8, 8, 9, 10, 11, 12, 13, 14]);
+	}
+
+	#[test]
+	fn consumer_gets_data_and_closed_events() {
+		let mut queue = EventQueue::new();
+		let (mut producer, mut consumer) = pair(16, &queue);
+		producer.write([1, 2, 3]);
+		match queue.next_event().unwrap().event_type {
+			events::DataAvailableEvent(3) => {},
+			_ => fail!("Expected a DataAvailableEvent")
+		}
+		drop(producer);
+		match queue.next_event().unwrap().event_type {
+			events::StreamClosedEvent => {},
+			_ => fail!("Expected a StreamClosedEvent")
+		}
+		assert_eq!(consumer.available(), 3);
+	}
+
+	#[test]
+	fn corrupted_positions_close_the_ring() {
+		let mut queue = EventQueue::new();
+		let (mut producer, mut consumer) = pair(16, &queue);
+		unsafe { (*producer.mapping.header()).write_pos.store(1000, SeqCst); }
+		let mut buf = [0u8, ..16];
+		assert_eq!(consumer.read(buf), 0);
+		match queue.next_event().unwrap().event_type {
+			events::IoErrorEvent(_) => {},
+			_ => fail!("Expected an IoErrorEvent")
+		}
+		match queue.next_event().unwrap().event_type {
+			events::StreamClosedEvent => {},
+			_ => fail!("Expected a StreamClosedEvent")
+		}
+		assert_eq!(producer.write([1]), 0);
+		assert!(producer.is_closed());
+	}
+}
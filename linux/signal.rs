@@ -0,0 +1,222 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::libc;
+use std::mem;
+use std::os;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::IoResult;
+use super::events;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::syscalls;
+use super::helpers;
+
+/**
+ * Receives signals through a signalfd and queues a SignalEvent for each one.
+ * The signals are blocked for the calling thread, so that their default
+ * action doesn't run. Signals that are sent to the process are only
+ * delivered to the signalfd if all other threads block them as well.
+ * Therefore a Signal should be created before any other thread is spawned,
+ * because new threads inherit the signal mask.
+ * Several Signals of a thread can watch the same signal. It is unblocked
+ * again when the last of them is dropped, unless it was blocked before the
+ * first one was created.
+ */
+pub struct Signal {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv fd: i32,
+	priv signals: ~[int],
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl events::EventSource for Signal {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl Signal {
+	pub fn create(signals: &[int], event_queue: &EventQueue) -> IoResult<~Signal> {
+		let mut mask = syscalls::sigset_t::new();
+		unsafe {
+			for signo in signals.iter() {
+				if syscalls::sigaddset(&mut mask, *signo as i32) == -1 {
+					return Err(helpers::last_error());
+				}
+			}
+			match helpers::watch_signals(&mask, signals) {
+				Err(err) => return Err(err),
+				Ok(()) => {}
+			}
+
+			let fd = syscalls::signalfd(-1, &mask, syscalls::SFD_NONBLOCK | syscalls::SFD_CLOEXEC);
+			if fd == -1 {
+				let err = helpers::last_error();
+				helpers::unwatch_signals(signals);
+				return Err(err);
+			}
+
+			let signal = ~Signal{
+				process_func: Signal::process_epoll_events,
+				fd: fd,
+				signals: signals.to_owned(),
+				event_queue: event_queue._get_impl(),
+				event_source_info: Rc::new(events::EventSourceInfo::new())
+			};
+			let callback: *libc::c_void = cast::transmute(&signal.process_func);
+			signal.event_queue.borrow().with_mut(|q|
+				q.register_fd(fd, syscalls::EPOLLIN, callback)
+			);
+			Ok(signal)
+		}
+	}
+
+	/// Returns the signals that are received by this source
+	pub fn signals<'a>(&'a self) -> &'a [int] {
+		self.signals.as_slice()
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let signal: *mut Signal = func_ptr as *mut Signal;
+			if epoll_events & syscalls::EPOLLIN == 0 { return; }
+
+			let size = mem::size_of::<syscalls::signalfd_siginfo>();
+			loop {
+				let mut info = syscalls::signalfd_siginfo::new();
+				let ret = helpers::retry(||
+					libc::read((*signal).fd,
+					           &mut info as *mut syscalls::signalfd_siginfo as *mut libc::c_void,
+					           size as libc::size_t) as i32
+				);
+				if ret as uint != size {
+					if ret == -1 {
+						let errno = os::errno() as int;
+						if errno != libc::EWOULDBLOCK as int && errno != libc::EAGAIN as int {
+							event_queue.push_back_event(events::Event {
+								event_type: events::IoErrorEvent(helpers::last_error()),
+								is_valid: true,
+								source_info: (*signal).event_source_info.clone()
+							});
+						}
+					}
+					return;
+				}
+
+				let signo = info.ssi_signo as int;
+				let signal_info = events::SignalInfo {
+					signo: signo,
+					code: info.ssi_code as int,
+					pid: info.ssi_pid,
+					uid: info.ssi_uid,
+					status: info.ssi_status as int
+				};
+				event_queue.push_back_event(events::Event {
+					event_type: events::SignalEvent(signo, signal_info),
+					is_valid: true,
+					source_info: (*signal).event_source_info.clone()
+				});
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl Drop for Signal {
+	fn drop(&mut self) {
+		let fd = self.fd;
+		self.event_queue.borrow().with_mut(|q| q.unregister_fd(fd));
+		unsafe { libc::close(fd); }
+		helpers::unwatch_signals(self.signals.as_slice());
+		self.remove_pending_events();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use native;
+	use eventqueue::EventQueue;
+	use events;
+	use syscalls;
+	use super::Signal;
+
+	// The tests run in parallel, so tests that raise signals use their own
+	static SIGUSR1: int = 10;
+	static SIGUSR2: int = 12;
+
+	extern {
+		fn raise(sig: i32) -> i32;
+	}
+
+	fn is_blocked(signo: int) -> bool {
+		let empty = syscalls::sigset_t::new();
+		let mut mask = syscalls::sigset_t::new();
+		unsafe {
+			syscalls::pthread_sigmask(syscalls::SIG_BLOCK, &empty, &mut mask);
+			syscalls::sigismember(&mask, signo as i32) == 1
+		}
+	}
+
+	#[test]
+	fn signal_stays_blocked_until_last_watcher_is_dropped() {
+		let queue = EventQueue::new();
+		let first = Signal::create([SIGUSR1], &queue).unwrap();
+		let second = Signal::create([SIGUSR1], &queue).unwrap();
+		assert!(is_blocked(SIGUSR1));
+		drop(first);
+		assert!(is_blocked(SIGUSR1));
+		drop(second);
+		assert!(!is_blocked(SIGUSR1));
+	}
+
+	#[test]
+	fn watchers_of_other_threads_are_not_counted() {
+		let queue = EventQueue::new();
+		let signal = Signal::create([SIGUSR1], &queue).unwrap();
+		let (port, chan) = Chan::new();
+		native::task::spawn(proc() {
+			// The thread inherits the blocked signal from its parent
+			let queue = EventQueue::new();
+			let signal = Signal::create([SIGUSR1], &queue).unwrap();
+			drop(signal);
+			chan.send(is_blocked(SIGUSR1));
+		});
+		assert!(port.recv());
+		drop(signal);
+		assert!(!is_blocked(SIGUSR1));
+	}
+
+	#[test]
+	fn raised_signal_is_queued() {
+		let mut queue = EventQueue::new();
+		let signal = Signal::create([SIGUSR2], &queue).unwrap();
+		unsafe { raise(SIGUSR2 as i32); }
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::SignalEvent(signo, _) => assert_eq!(signo, SIGUSR2),
+			_ => fail!("Expected a SignalEvent")
+		}
+		assert!(event.originates_from(signal));
+	}
+}
+
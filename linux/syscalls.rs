@@ -98,6 +98,20 @@ extern {
 			   maxevents: i32, timeout: i32) -> i32;
 }
 
+/// Poll calls
+extern {
+	pub fn poll(fds: *mut pollfd, nfds: libc::c_ulong, timeout: i32) -> i32;
+}
+
+pub struct pollfd {
+	fd: i32,
+	events: i16,
+	revents: i16
+}
+
+pub static POLLIN: i16 = 0x001;
+pub static POLLOUT: i16 = 0x004;
+
 /// Timerfd calls
 extern {
 	pub fn timerfd_create(clockid: i32, flags: i32) -> i32;
@@ -107,6 +121,9 @@ extern {
 	pub fn timerfd_gettime(fd: i32, curr_value: *itimerspec) -> i32;
 }
 
+pub static TFD_TIMER_ABSTIME: i32 = 1;
+pub static TFD_TIMER_CANCEL_ON_SET: i32 = 2;
+
 pub struct itimerspec {
 	it_interval: libc::timespec,	/* Interval for periodic timer */
 	it_value: libc::timespec		/* Initial expiration */
@@ -127,6 +144,48 @@ impl itimerspec {
 	}
 }
 
+/// Time calls
+extern {
+	pub fn clock_gettime(clockid: i32, tp: *mut libc::timespec) -> i32;
+	pub fn localtime_r(timep: *libc::time_t, result: *mut tm) -> *mut tm;
+	pub fn gmtime_r(timep: *libc::time_t, result: *mut tm) -> *mut tm;
+	pub fn mktime(tm: *mut tm) -> libc::time_t;
+	pub fn timegm(tm: *mut tm) -> libc::time_t;
+	pub fn tzset();
+}
+
+pub struct tm {
+	tm_sec: i32,		/* Seconds (0-60) */
+	tm_min: i32,		/* Minutes (0-59) */
+	tm_hour: i32,		/* Hours (0-23) */
+	tm_mday: i32,		/* Day of the month (1-31) */
+	tm_mon: i32,		/* Month (0-11) */
+	tm_year: i32,		/* Year - 1900 */
+	tm_wday: i32,		/* Day of the week (0-6, Sunday = 0) */
+	tm_yday: i32,		/* Day in the year (0-365, 1 Jan = 0) */
+	tm_isdst: i32,		/* Daylight saving time */
+	tm_gmtoff: libc::c_long,	/* Seconds east of UTC */
+	tm_zone: *libc::c_char	/* Timezone abbreviation */
+}
+
+impl tm {
+	pub fn new() -> tm {
+		tm {
+			tm_sec: 0,
+			tm_min: 0,
+			tm_hour: 0,
+			tm_mday: 0,
+			tm_mon: 0,
+			tm_year: 0,
+			tm_wday: 0,
+			tm_yday: 0,
+			tm_isdst: 0,
+			tm_gmtoff: 0,
+			tm_zone: 0 as *libc::c_char
+		}
+	}
+}
+
 extern {
 	pub fn eventfd(initval: u32, flags: i32) -> i32;
 	pub fn ioctl(fd: i32, req: i32, ...) -> i32;
@@ -134,8 +193,123 @@ extern {
 
 	pub fn getsockopt(socket: libc::c_int, level: libc::c_int, name: libc::c_int,
 					  value: *libc::c_void, option_len: *libc::socklen_t) -> libc::c_int;
+	pub fn socketpair(domain: i32, ty: i32, protocol: i32, sv: *mut i32) -> i32;
+}
+
+/// Shared memory calls
+extern {
+	pub fn syscall(number: libc::c_long, ...) -> libc::c_long;
+	pub fn ftruncate(fd: i32, length: libc::off_t) -> i32;
+}
+
+#[cfg(target_arch = "x86_64")]
+pub static SYS_MEMFD_CREATE: libc::c_long = 319;
+#[cfg(target_arch = "x86")]
+pub static SYS_MEMFD_CREATE: libc::c_long = 356;
+#[cfg(target_arch = "arm")]
+pub static SYS_MEMFD_CREATE: libc::c_long = 385;
+#[cfg(target_arch = "aarch64")]
+pub static SYS_MEMFD_CREATE: libc::c_long = 279;
+#[cfg(target_arch = "mips")]
+pub static SYS_MEMFD_CREATE: libc::c_long = 4354;
+
+pub static MFD_CLOEXEC: u32 = 1;
+
+/// Signal calls
+extern {
+	pub fn sigemptyset(set: *mut sigset_t) -> i32;
+	pub fn sigaddset(set: *mut sigset_t, signum: i32) -> i32;
+	pub fn sigismember(set: *sigset_t, signum: i32) -> i32;
+	pub fn pthread_sigmask(how: i32, set: *sigset_t, oldset: *mut sigset_t) -> i32;
+	pub fn signalfd(fd: i32, mask: *sigset_t, flags: i32) -> i32;
+}
+
+pub struct sigset_t {
+	val: [u64, ..16]
+}
+
+impl sigset_t {
+	pub fn new() -> sigset_t {
+		let mut set = sigset_t { val: [0, ..16] };
+		unsafe { sigemptyset(&mut set); }
+		set
+	}
+}
+
+pub struct signalfd_siginfo {
+	ssi_signo: u32,		/* Signal number */
+	ssi_errno: i32,		/* Error number (unused) */
+	ssi_code: i32,		/* Signal code */
+	ssi_pid: u32,		/* PID of sender */
+	ssi_uid: u32,		/* Real UID of sender */
+	ssi_fd: i32,		/* File descriptor (SIGIO) */
+	ssi_tid: u32,		/* Kernel timer ID (POSIX timers) */
+	ssi_band: u32,		/* Band event (SIGIO) */
+	ssi_overrun: u32,	/* POSIX timer overrun count */
+	ssi_trapno: u32,	/* Trap number that caused signal */
+	ssi_status: i32,	/* Exit status or signal (SIGCHLD) */
+	ssi_int: i32,		/* Integer sent by sigqueue */
+	ssi_ptr: u64,		/* Pointer sent by sigqueue */
+	ssi_utime: u64,		/* User CPU time consumed (SIGCHLD) */
+	ssi_stime: u64,		/* System CPU time consumed (SIGCHLD) */
+	ssi_addr: u64,		/* Address that generated signal */
+	ssi_addr_lsb: u16,	/* Least significant bit of address */
+	__pad: [u8, ..46]
+}
+
+impl signalfd_siginfo {
+	pub fn new() -> signalfd_siginfo {
+		signalfd_siginfo {
+			ssi_signo: 0, ssi_errno: 0, ssi_code: 0, ssi_pid: 0,
+			ssi_uid: 0, ssi_fd: 0, ssi_tid: 0, ssi_band: 0,
+			ssi_overrun: 0, ssi_trapno: 0, ssi_status: 0, ssi_int: 0,
+			ssi_ptr: 0, ssi_utime: 0, ssi_stime: 0, ssi_addr: 0,
+			ssi_addr_lsb: 0, __pad: [0, ..46]
+		}
+	}
 }
 
+pub static SIG_BLOCK: i32 = 0;
+pub static SIG_UNBLOCK: i32 = 1;
+pub static SIG_SETMASK: i32 = 2;
+
+pub static SFD_NONBLOCK: i32 = 0x800;
+pub static SFD_CLOEXEC: i32 = 0x80000;
+
+#[cfg(target_arch = "x86_64")]
+#[cfg(target_arch = "x86")]
+#[cfg(target_arch = "arm")]
+#[cfg(target_arch = "aarch64")]
+pub static SIGCHLD: i32 = 17;
+#[cfg(target_arch = "mips")]
+pub static SIGCHLD: i32 = 18;
+
+/// Process calls
+extern {
+	pub fn pipe2(fds: *mut i32, flags: i32) -> i32;
+	pub fn execvpe(file: *libc::c_char, argv: **libc::c_char, envp: **libc::c_char) -> i32;
+	pub fn _exit(status: i32);
+	pub fn setpgid(pid: libc::pid_t, pgid: libc::pid_t) -> i32;
+	pub fn waitpid(pid: libc::pid_t, status: *mut i32, options: i32) -> libc::pid_t;
+	pub fn kill(pid: libc::pid_t, sig: i32) -> i32;
+}
+
+#[cfg(target_arch = "x86_64")]
+#[cfg(target_arch = "x86")]
+#[cfg(target_arch = "arm")]
+#[cfg(target_arch = "aarch64")]
+pub static SYS_PIDFD_OPEN: libc::c_long = 434;
+#[cfg(target_arch = "mips")]
+pub static SYS_PIDFD_OPEN: libc::c_long = 4434;
+
+pub static O_CLOEXEC: i32 = 0x80000;
+pub static WNOHANG: i32 = 1;
+pub static ECHILD: i32 = 10;
+pub static SIGKILL: i32 = 9;
+
+pub static EFD_NONBLOCK: i32 = 0x800;
+pub static EFD_CLOEXEC: i32 = 0x80000;
+
 pub fn set_fd_blocking(fd: i32, blocking: bool) {
 	unsafe {
 		let mut flags = fcntl(fd, F_GETFL, 0);
@@ -149,6 +323,8 @@ pub fn set_fd_blocking(fd: i32, blocking: bool) {
 	}
 }
 
+pub static ECANCELED: i32 = 125;
+
 pub static FIONREAD: i32 = 0x541B;
 pub static O_NONBLOCK: i32 = 0x800;
 pub static F_GETFL: i32 = 3;	/* Get file status flags.  */
@@ -156,6 +332,11 @@ pub static F_SETFL: i32 = 4;	/* Set file status flags.  */
 
 pub static SOCK_CLOEXEC: i32 = 0x80000;	/* Atomically set close-on-exec flag for the new descriptor(s).  */
 pub static SOCK_NONBLOCK: i32 = 0x800; /* Atomically mark descriptor(s) as non-blocking.  */
+pub static SOCK_SEQPACKET: i32 = 5;
+pub static AF_UNIX: i32 = 1;
+
+pub static MSG_TRUNC: i32 = 0x20;	/* Return the real length of a truncated packet.  */
+pub static MSG_NOSIGNAL: i32 = 0x4000;	/* Don't raise SIGPIPE.  */
 
 // Socket options
 pub static SO_DEBUG: i32 = 1;
@@ -27,9 +27,16 @@ pub struct Timer {
 	priv is_active: bool,
 	priv interval: u32,
 	priv singleshot: bool,
+	priv slack: u32,
+	priv coalesced: bool,
 	priv epoll_registered: bool,
+	// Stopped by TimerGroup::pause and restarted by resume
+	priv paused: bool,
+	// Queued events were already removed by a TimerGroup
+	priv events_purged: bool,
 	priv event_queue: Rc<RefCell<EventQueueImpl>>,
-	priv event_source_info: Rc<events::EventSourceInfo>
+	priv event_source_info: Rc<events::EventSourceInfo>,
+	priv clock: Option<VirtualClock>
 }
 
 impl Timer {
@@ -46,14 +53,45 @@ impl Timer {
 				interval: 0,
 				is_active: false,
 				singleshot: false,
+				slack: 0,
+				coalesced: false,
 				epoll_registered: false,
+				paused: false,
+				events_purged: false,
 				event_queue: event_queue._get_impl(),
 				process_func: Timer::process_epoll_events,
-				event_source_info: Rc::new(events::EventSourceInfo::new())
+				event_source_info: Rc::new(events::EventSourceInfo::new()),
+				clock: None
 			})
 		}
 	}
 
+	/**
+	 * Creates a timer that is driven by a virtual clock instead of a timerfd.
+	 * The timer only expires when the clock is advanced.
+	 * The clock refers to the timer by its address while it is active,
+	 * so the timer must stay in the returned box. Dropping it removes it
+	 * from the clock.
+	 */
+	pub fn create_with_clock(event_queue: &eventqueue::EventQueue, clock: &VirtualClock) -> IoResult<~Timer> {
+		Ok(~Timer{
+			fd: -1,
+			interval: 0,
+			is_active: false,
+			singleshot: false,
+			slack: 0,
+			coalesced: false,
+			epoll_registered: false,
+			paused: false,
+			events_purged: false,
+			event_queue: event_queue._get_impl(),
+			process_func: Timer::process_epoll_events,
+			event_source_info: Rc::new(events::EventSourceInfo::new()),
+			clock: Some(clock.clone())
+		})
+	}
+
+	/// Sets the interval in milliseconds. Takes effect on the next start.
 	pub fn set_interval(&mut self, interval: u32) {
 		self.interval = interval;
 	}
@@ -74,8 +112,49 @@ impl Timer {
 		self.is_active
 	}
 
+	/**
+	 * Allows the timer to expire up to `slack` milliseconds late.
+	 * Timers with slack share their wakeups with other timers of the same
+	 * EventQueue whose windows overlap. Takes effect on the next start.
+	 * Like without slack, a TimerEvent is queued for each expiration. If a
+	 * periodic timer expired several times before the EventQueue was polled,
+	 * all of these events are queued at once.
+	 * Timers that are driven by a VirtualClock ignore the slack.
+	 */
+	pub fn set_slack(&mut self, slack: u32) {
+		self.slack = slack;
+	}
+
+	pub fn get_slack(&self) -> u32 {
+		self.slack
+	}
+
 	pub fn stop(&mut self) {
+		// A paused timer stays stopped when its group is resumed
+		self.paused = false;
 		if !self.is_active { return; }
+		self.disarm();
+		self.remove_pending_events();
+	}
+
+	/// Stops the timer without removing already queued events
+	fn disarm(&mut self) {
+		if self.clock.is_some() {
+			let timer: *mut Timer = self;
+			self.clock.get_ref().remove_timer(timer);
+			self.is_active = false;
+			return;
+		}
+
+		if self.coalesced {
+			let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+			self.event_queue.borrow().with_mut(
+				|q|q.remove_slack_timer(callback)
+			);
+			self.coalesced = false;
+			self.is_active = false;
+			return;
+		}
 
 		let new_value = syscalls::itimerspec::new(); // init to 0
 
@@ -91,12 +170,30 @@ impl Timer {
 		);
 		self.epoll_registered = false;
 		self.is_active = false;
-		self.remove_pending_events();
 	}
 
 	pub fn start(&mut self) {
+		self.paused = false;
 		if self.is_active || self.interval == 0 { return; }
 
+		if self.clock.is_some() {
+			let timer: *mut Timer = self;
+			let period = if self.singleshot { 0 } else { self.interval };
+			self.clock.get_ref().add_timer(timer, self.interval, period);
+			self.is_active = true;
+			return;
+		}
+
+		if self.slack != 0 {
+			let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+			self.event_queue.borrow().with_mut(|q|
+				q.add_slack_timer(callback, self.interval, self.slack, self.singleshot)
+			);
+			self.coalesced = true;
+			self.is_active = true;
+			return;
+		}
+
 		let mut new_value = syscalls::itimerspec::new();
 		new_value.it_value.tv_sec = (self.interval / 1000u32) as libc::time_t;
 		new_value.it_value.tv_nsec = (self.interval % 1000u32) as libc::c_long;
@@ -137,6 +234,21 @@ impl Timer {
 		unsafe {
 			let timer: *mut Timer = func_ptr as *mut Timer;
 
+			if (*timer).coalesced {
+				// Invoked by the event queue. There is no fd to read.
+				let e = events::Event {
+					event_type: events::TimerEvent,
+					is_valid: true,
+					source_info: (*timer).event_source_info.clone()
+				};
+				event_queue.push_back_event(e);
+				if (*timer).singleshot {
+					(*timer).is_active = false;
+					(*timer).coalesced = false;
+				}
+				return;
+			}
+
 			if epoll_events & syscalls::EPOLLIN != 0 {
 				let buffer = [0, ..8];
 
@@ -165,6 +277,19 @@ impl Timer {
 		}
 	}
 
+	/// Called by the VirtualClock. `last` is true if the timer isn't rescheduled.
+	fn virtual_expired(&mut self, last: bool) {
+		let e = events::Event {
+			event_type: events::TimerEvent,
+			is_valid: true,
+			source_info: self.event_source_info.clone()
+		};
+		self.event_queue.borrow().with_mut(|q| q.push_back_event(e));
+		if last {
+			self.is_active = false;
+		}
+	}
+
 	fn remove_pending_events(&mut self) {
 		self.event_queue.borrow().with_mut(|q|
 			q.remove_pending_events(
@@ -178,8 +303,20 @@ impl Drop for Timer {
 	fn drop(&mut self) {
 		// Don't call close because this won't deque already
 		// queued events if the timer is inactive
-		self.remove_pending_events();
-		if self.fd != 0 {
+		if !self.events_purged {
+			self.remove_pending_events();
+		}
+		if self.coalesced {
+			let callback: *libc::c_void = unsafe { cast::transmute(&self.process_func) };
+			self.event_queue.borrow().with_mut(
+				|q|q.remove_slack_timer(callback)
+			);
+		}
+		if self.clock.is_some() {
+			let timer: *mut Timer = self;
+			self.clock.get_ref().remove_timer(timer);
+		}
+		if self.fd != -1 {
 			unsafe { libc::close(self.fd); }
 		}
 	}
@@ -189,4 +326,379 @@ impl events::EventSource for Timer {
 	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
 		&self.event_source_info
 	}
-}
\ No newline at end of file
+}
+
+/**
+ * Owns several timers that belong together, e.g. the timeouts of a connection.
+ * Stopping or dropping the group stops all timers and purges their queued
+ * events from the EventQueue in a single pass.
+ */
+pub struct TimerGroup {
+	priv timers: ~[~Timer],
+	priv event_queue: Rc<RefCell<EventQueueImpl>>
+}
+
+impl TimerGroup {
+	pub fn new(event_queue: &eventqueue::EventQueue) -> TimerGroup {
+		TimerGroup {
+			timers: ~[],
+			event_queue: event_queue._get_impl()
+		}
+	}
+
+	/**
+	 * Moves a timer into the group and returns its index.
+	 * The timer must have been created for the same EventQueue as the group.
+	 */
+	pub fn add(&mut self, timer: ~Timer) -> uint {
+		self.timers.push(timer);
+		self.timers.len() - 1
+	}
+
+	pub fn len(&self) -> uint {
+		self.timers.len()
+	}
+
+	pub fn get<'a>(&'a mut self, index: uint) -> &'a mut Timer {
+		&mut *self.timers[index]
+	}
+
+	/// Returns the index of the timer the event originates from
+	pub fn find_source(&self, event: &events::Event) -> Option<uint> {
+		self.timers.iter().position(|timer| event.originates_from(&**timer))
+	}
+
+	/// Stops all timers and removes their queued events
+	pub fn stop_all(&mut self) {
+		for timer in self.timers.mut_iter() {
+			timer.paused = false;
+			if timer.is_active {
+				timer.disarm();
+			}
+		}
+		self.remove_pending_events();
+	}
+
+	/**
+	 * Stops all active timers and removes their queued events.
+	 * `resume()` restarts these timers with their full interval, except
+	 * for timers that were started or stopped in the meantime.
+	 */
+	pub fn pause(&mut self) {
+		for timer in self.timers.mut_iter() {
+			if timer.is_active {
+				timer.disarm();
+				timer.paused = true;
+			}
+		}
+		self.remove_pending_events();
+	}
+
+	pub fn resume(&mut self) {
+		for timer in self.timers.mut_iter() {
+			if timer.paused {
+				timer.start();
+			}
+		}
+	}
+
+	/// Stops and drops all timers of the group
+	pub fn clear(&mut self) {
+		self.stop_all();
+		self.release_timers();
+		self.timers.clear();
+	}
+
+	/// Lets the timers skip their own purge on drop after the group's purge
+	fn release_timers(&mut self) {
+		for timer in self.timers.mut_iter() {
+			timer.events_purged = true;
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		let sources: ~[*events::EventSourceInfo] = self.timers.iter().map(|timer|
+			timer.event_source_info.borrow() as *events::EventSourceInfo
+		).collect();
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(|ev|
+				sources.contains(&(ev.source_info.borrow() as *events::EventSourceInfo)))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl Drop for TimerGroup {
+	fn drop(&mut self) {
+		self.stop_all();
+		self.release_timers();
+	}
+}
+
+/**
+ * A manually driven clock for testing timer based code.
+ * Timers that are created with `Timer::create_with_clock` don't use
+ * a timerfd. Their events are queued in chronological order when
+ * the clock is advanced.
+ */
+pub struct VirtualClock {
+	priv data: Rc<RefCell<VirtualClockData>>
+}
+
+struct VirtualClockData {
+	now: u64,
+	timers: ~[VirtualTimerEntry]
+}
+
+struct VirtualTimerEntry {
+	timer: *mut Timer,
+	deadline: u64,
+	// Taken from the timer on start like the timerfd settings, 0 for singleshot timers
+	period: u64
+}
+
+impl VirtualClock {
+	pub fn new() -> VirtualClock {
+		VirtualClock {
+			data: Rc::new(RefCell::new(VirtualClockData {
+				now: 0,
+				timers: ~[]
+			}))
+		}
+	}
+
+	/// Returns the time in milliseconds since the clock was created
+	pub fn now(&self) -> u64 {
+		self.data.borrow().with(|d| d.now)
+	}
+
+	/**
+	 * Advances the clock by `duration` milliseconds and queues a TimerEvent
+	 * for each expiration that happened in this time span.
+	 * Timers with equal deadlines expire in the order they were started.
+	 */
+	pub fn advance(&self, duration: u32) {
+		let target = self.now() + duration as u64;
+		loop {
+			let expired = self.data.borrow().with_mut(|d| d.pop_expired(target));
+			match expired {
+				Some((timer, last)) => unsafe { (*timer).virtual_expired(last) },
+				None => break
+			}
+		}
+		self.data.borrow().with_mut(|d| d.now = target);
+	}
+
+	fn add_timer(&self, timer: *mut Timer, interval: u32, period: u32) {
+		self.data.borrow().with_mut(|d| {
+			let deadline = d.now + interval as u64;
+			d.timers.push(VirtualTimerEntry {
+				timer: timer,
+				deadline: deadline,
+				period: period as u64
+			});
+		});
+	}
+
+	fn remove_timer(&self, timer: *mut Timer) {
+		self.data.borrow().with_mut(|d|
+			d.timers.retain(|entry| entry.timer != timer)
+		);
+	}
+}
+
+impl Clone for VirtualClock {
+	fn clone(&self) -> VirtualClock {
+		VirtualClock {
+			data: self.data.clone()
+		}
+	}
+}
+
+impl VirtualClockData {
+	/// Returns the next expired timer and whether it was removed from the clock
+	fn pop_expired(&mut self, target: u64) -> Option<(*mut Timer, bool)> {
+		let mut next: Option<uint> = None;
+		for (i, entry) in self.timers.iter().enumerate() {
+			if entry.deadline > target { continue; }
+			match next {
+				Some(n) if self.timers[n].deadline <= entry.deadline => {},
+				_ => { next = Some(i); }
+			}
+		}
+
+		match next {
+			None => None,
+			Some(i) => {
+				let timer = self.timers[i].timer;
+				self.now = self.timers[i].deadline;
+				let mut entry = self.timers.remove(i);
+				if entry.period == 0 {
+					return Some((timer, true));
+				}
+				// Move the timer behind others with the same deadline
+				entry.deadline += entry.period;
+				self.timers.push(entry);
+				Some((timer, false))
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use super::{Timer, TimerGroup, VirtualClock};
+
+	fn pending(queue: &EventQueue) -> uint {
+		queue._get_impl().borrow().with(|q| q.pending_events())
+	}
+
+	fn expect_timer_event(queue: &mut EventQueue, timer: &Timer) {
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::TimerEvent => {},
+			_ => fail!("Expected a TimerEvent")
+		}
+		assert!(event.originates_from(timer));
+	}
+
+	#[test]
+	fn periodic_timer_fires_once_per_interval() {
+		let queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut timer = Timer::create_with_clock(&queue, &clock).unwrap();
+		timer.set_interval(100);
+		timer.start();
+		clock.advance(99);
+		assert_eq!(pending(&queue), 0);
+		clock.advance(251);
+		assert_eq!(pending(&queue), 3);
+		assert_eq!(clock.now(), 350);
+		assert!(timer.is_active());
+	}
+
+	#[test]
+	fn singleshot_timer_fires_once() {
+		let queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut timer = Timer::create_with_clock(&queue, &clock).unwrap();
+		timer.set_interval(10);
+		timer.set_singleshot(true);
+		timer.start();
+		clock.advance(1000);
+		assert_eq!(pending(&queue), 1);
+		assert!(!timer.is_active());
+	}
+
+	#[test]
+	fn timers_expire_in_chronological_order() {
+		let mut queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut slow = Timer::create_with_clock(&queue, &clock).unwrap();
+		let mut fast = Timer::create_with_clock(&queue, &clock).unwrap();
+		slow.set_interval(30);
+		fast.set_interval(20);
+		slow.start();
+		fast.start();
+		clock.advance(50);
+		assert_eq!(pending(&queue), 3);
+		expect_timer_event(&mut queue, fast);
+		expect_timer_event(&mut queue, slow);
+		expect_timer_event(&mut queue, fast);
+	}
+
+	#[test]
+	fn stopped_timer_does_not_fire() {
+		let queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut timer = Timer::create_with_clock(&queue, &clock).unwrap();
+		timer.set_interval(100);
+		timer.start();
+		clock.advance(150);
+		assert_eq!(pending(&queue), 1);
+		timer.stop();
+		assert_eq!(pending(&queue), 0);
+		clock.advance(1000);
+		assert_eq!(pending(&queue), 0);
+		assert!(!timer.is_active());
+	}
+
+	#[test]
+	fn interval_change_takes_effect_on_restart() {
+		let queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut timer = Timer::create_with_clock(&queue, &clock).unwrap();
+		timer.set_interval(10);
+		timer.start();
+		timer.set_interval(0);
+		clock.advance(35);
+		assert_eq!(pending(&queue), 3);
+		timer.stop();
+		timer.start(); // An interval of 0 doesn't start the timer
+		assert!(!timer.is_active());
+	}
+
+	#[test]
+	fn dropped_timer_is_removed_from_clock() {
+		let queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		{
+			let mut timer = Timer::create_with_clock(&queue, &clock).unwrap();
+			timer.set_interval(10);
+			timer.start();
+		}
+		clock.advance(100);
+		assert_eq!(pending(&queue), 0);
+	}
+
+	#[test]
+	fn group_resumes_only_paused_timers() {
+		let queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut group = TimerGroup::new(&queue);
+		let running = group.add(Timer::create_with_clock(&queue, &clock).unwrap());
+		let stopped = group.add(Timer::create_with_clock(&queue, &clock).unwrap());
+		let idle = group.add(Timer::create_with_clock(&queue, &clock).unwrap());
+		for i in range(0, group.len()) {
+			group.get(i).set_interval(100);
+		}
+		group.get(running).start();
+		group.get(stopped).start();
+
+		clock.advance(150);
+		assert_eq!(pending(&queue), 2);
+		group.pause();
+		assert_eq!(pending(&queue), 0);
+		assert!(!group.get(running).is_active());
+
+		group.get(stopped).stop();
+		group.resume();
+		assert!(group.get(running).is_active());
+		assert!(!group.get(stopped).is_active());
+		assert!(!group.get(idle).is_active());
+		clock.advance(100);
+		assert_eq!(pending(&queue), 1);
+	}
+
+	#[test]
+	fn clearing_group_removes_events() {
+		let queue = EventQueue::new();
+		let clock = VirtualClock::new();
+		let mut group = TimerGroup::new(&queue);
+		for _ in range(0, 3) {
+			let mut timer = Timer::create_with_clock(&queue, &clock).unwrap();
+			timer.set_interval(10);
+			timer.start();
+			group.add(timer);
+		}
+		clock.advance(25);
+		assert_eq!(pending(&queue), 6);
+		group.clear();
+		assert_eq!(group.len(), 0);
+		assert_eq!(pending(&queue), 0);
+		clock.advance(100);
+		assert_eq!(pending(&queue), 0);
+	}
+}
@@ -0,0 +1,448 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::libc;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::unstable::mutex::Mutex;
+use std::sync::arc::UnsafeArc;
+
+use super::events;
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::syscalls;
+use super::helpers;
+
+pub struct WatchSender<T> {
+	priv data: UnsafeArc<SharedWatchData<T>>
+}
+
+pub struct BlockingWatcher<T> {
+	priv data: UnsafeArc<SharedWatchData<T>>,
+	priv slot: UnsafeArc<WatcherSlot>
+}
+
+struct SharedWatchData<T> {
+	mutex: Mutex,
+	value: T,
+	nr_senders: uint,
+	watchers: ~[UnsafeArc<WatcherSlot>]
+}
+
+/// Change notification state of a single watcher
+struct WatcherSlot {
+	mutex: Mutex,
+	changed: bool,
+	closed: bool,
+	alive: bool,
+	epoll_fd: i32
+}
+
+/**
+ * A channel that only holds the latest value.
+ * Watchers are notified when the value changes, but intermediate
+ * values that were replaced before a watcher looked at them are lost.
+ */
+pub struct Watch<T>;
+
+impl <T:Send> Watch<T> {
+	pub fn create(initial: T) -> WatchSender<T> {
+		WatchSender {
+			data: UnsafeArc::new(SharedWatchData {
+				mutex: unsafe { Mutex::new() },
+				value: initial,
+				nr_senders: 1,
+				watchers: ~[]
+			})
+		}
+	}
+}
+
+/// Notifies a watcher. Must be called with the slot mutex held.
+unsafe fn notify_watcher(slot: *mut WatcherSlot) {
+	if (*slot).epoll_fd == -1 {
+		(*slot).mutex.signal();
+	}
+	else if helpers::signal_eventfd((*slot).epoll_fd) == -1 {
+		(*slot).mutex.unlock();
+		fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
+	}
+}
+
+impl<T:Send> WatchSender<T> {
+	/// Creates a watcher that is notified about all changes after this call
+	pub fn subscribe(&self) -> BlockingWatcher<T> {
+		let slot = UnsafeArc::new(WatcherSlot {
+			mutex: unsafe { Mutex::new() },
+			changed: false,
+			closed: false,
+			alive: true,
+			epoll_fd: -1
+		});
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).watchers.push(slot.clone());
+			(*data).mutex.unlock();
+		}
+		BlockingWatcher{data: self.data.clone(), slot: slot}
+	}
+
+	/// Replaces the value and notifies all watchers that haven't been notified yet
+	pub fn send(&self, value: T) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).value = value;
+			(*data).watchers.retain(|slot| {
+				let slot = slot.get();
+				(*slot).mutex.lock();
+				let alive = (*slot).alive;
+				if alive && !(*slot).changed {
+					(*slot).changed = true;
+					notify_watcher(slot);
+				}
+				(*slot).mutex.unlock();
+				alive
+			});
+			(*data).mutex.unlock();
+		}
+	}
+
+	/**
+	 * Calls the function with a reference to the current value.
+	 * The value is locked during the call, so the function must not call
+	 * `send` or `with_value` of the same Watch. Use `get` to work on a copy.
+	 */
+	pub fn with_value<U>(&self, f: |&T| -> U) -> U {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let ret = f(&(*data).value);
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for WatchSender<T> {
+	fn drop(&mut self) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_senders -= 1;
+			if (*data).nr_senders == 0 {
+				for slot in (*data).watchers.iter() {
+					let slot = slot.get();
+					(*slot).mutex.lock();
+					(*slot).closed = true;
+					notify_watcher(slot);
+					(*slot).mutex.unlock();
+				}
+				(*data).watchers.clear();
+			}
+			(*data).mutex.unlock();
+		}
+	}
+}
+
+impl<T:Send> Clone for WatchSender<T> {
+	fn clone(&self) -> WatchSender<T> {
+		let new = WatchSender{data: self.data.clone()};
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_senders += 1;
+			(*data).mutex.unlock();
+		}
+		new
+	}
+}
+
+impl<T:Send> BlockingWatcher<T> {
+	/**
+	 * Blocks until the value changes. Returns false when all senders are
+	 * gone and there is no unseen change.
+	 */
+	pub fn wait_changed(&self) -> bool {
+		let slot = self.slot.get();
+		unsafe {
+			(*slot).mutex.lock();
+			while !(*slot).changed && !(*slot).closed {
+				(*slot).mutex.wait();
+			}
+			let changed = (*slot).changed;
+			(*slot).changed = false;
+			(*slot).mutex.unlock();
+			changed
+		}
+	}
+
+	/**
+	 * Calls the function with a reference to the current value.
+	 * The value is locked during the call, so the function must not call
+	 * `send` or `with_value` of the same Watch. Use `get` to work on a copy.
+	 */
+	pub fn with_value<U>(&self, f: |&T| -> U) -> U {
+		let slot = self.slot.get();
+		let data = self.data.get();
+		unsafe {
+			(*slot).mutex.lock();
+			(*slot).changed = false;
+			(*slot).mutex.unlock();
+			(*data).mutex.lock();
+			let ret = f(&(*data).value);
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+
+	pub fn is_closed(&self) -> bool {
+		let slot = self.slot.get();
+		unsafe {
+			(*slot).mutex.lock();
+			let closed = (*slot).closed;
+			(*slot).mutex.unlock();
+			closed
+		}
+	}
+}
+
+impl<T:Send+Clone> WatchSender<T> {
+	/// Returns a copy of the current value
+	pub fn get(&self) -> T {
+		self.with_value(|v| v.clone())
+	}
+}
+
+impl<T:Send+Clone> BlockingWatcher<T> {
+	/// Returns a copy of the current value
+	pub fn get(&self) -> T {
+		self.with_value(|v| v.clone())
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for BlockingWatcher<T> {
+	fn drop(&mut self) {
+		let slot = self.slot.get();
+		unsafe {
+			(*slot).mutex.lock();
+			(*slot).alive = false;
+			(*slot).mutex.unlock();
+		}
+	}
+}
+
+/**
+ * An evented watcher.
+ * Changes are coalesced into a single ValueChangedEvent that stays pending
+ * until the value was looked at with `with_value` or `get`.
+ * A ChannelClosedEvent is queued when all senders are gone.
+ */
+pub struct Watcher<T> {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv watcher: BlockingWatcher<T>,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>,
+	priv event_pending: bool,
+	priv closed_reported: bool
+}
+
+impl<T> events::EventSource for Watcher<T> {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl<T:Send> Watcher<T> {
+	pub fn from_blocking_watcher(blocking_watcher: BlockingWatcher<T>, event_queue: &EventQueue) -> ~Watcher<T> {
+		let watcher = ~Watcher{
+			watcher: blocking_watcher,
+			event_queue: event_queue._get_impl(),
+			process_func: Watcher::<T>::process_epoll_events,
+			event_source_info: Rc::new(events::EventSourceInfo::new()),
+			event_pending: false,
+			closed_reported: false
+		};
+
+		let fd = unsafe { syscalls::eventfd(0, 0) };
+		if fd == -1 {
+			fail!("Creating eventfd for watcher failed: {}", helpers::last_error().desc);
+		}
+		let callback: *libc::c_void = unsafe { cast::transmute(&watcher.process_func) };
+		watcher.event_queue.borrow().with_mut(|q|
+			q.register_fd(fd, syscalls::EPOLLIN, callback)
+		);
+
+		let slot = watcher.watcher.slot.get();
+		unsafe {
+			(*slot).mutex.lock();
+			(*slot).epoll_fd = fd;
+			if (*slot).changed || (*slot).closed {
+				notify_watcher(slot);
+			}
+			(*slot).mutex.unlock();
+		}
+		watcher
+	}
+
+	/// Like `BlockingWatcher::with_value`. The value is locked during the call.
+	pub fn with_value<U>(&mut self, f: |&T| -> U) -> U {
+		self.event_pending = false;
+		self.watcher.with_value(f)
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let watcher: *mut Watcher<T> = func_ptr as *mut Watcher<T>;
+			let slot = (*watcher).watcher.slot.get();
+
+			if epoll_events & syscalls::EPOLLIN != 0 {
+				let buffer = [0, ..8];
+
+				let ret = helpers::retry(||
+					libc::read((*slot).epoll_fd,
+						       buffer.as_ptr() as *mut libc::c_void,
+						       buffer.len() as libc::size_t) as i32
+				);
+
+				if ret == 8 {
+					(*slot).mutex.lock();
+					let changed = (*slot).changed;
+					let closed = (*slot).closed;
+					(*slot).changed = false;
+					(*slot).mutex.unlock();
+
+					if changed && !(*watcher).event_pending {
+						(*watcher).event_pending = true;
+						event_queue.push_back_event(events::Event {
+							event_type: events::ValueChangedEvent,
+							is_valid: true,
+							source_info: (*watcher).event_source_info.clone()
+						});
+					}
+					if closed && !(*watcher).closed_reported {
+						(*watcher).closed_reported = true;
+						event_queue.push_back_event(events::Event {
+							event_type: events::ChannelClosedEvent,
+							is_valid: true,
+							source_info: (*watcher).event_source_info.clone()
+						});
+					}
+				}
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+impl<T:Send+Clone> Watcher<T> {
+	pub fn get(&mut self) -> T {
+		self.with_value(|v| v.clone())
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for Watcher<T> {
+	fn drop(&mut self) {
+		let slot = self.watcher.slot.get();
+		unsafe {
+			(*slot).mutex.lock();
+			if (*slot).epoll_fd != -1 {
+				libc::close((*slot).epoll_fd);
+				(*slot).epoll_fd = -1;
+			}
+			(*slot).mutex.unlock();
+		}
+		self.remove_pending_events();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use super::{Watch, Watcher};
+
+	fn pending(queue: &EventQueue) -> uint {
+		queue._get_impl().borrow().with(|q| q.pending_events())
+	}
+
+	fn poll(queue: &EventQueue) {
+		queue._get_impl().borrow().with_mut(|q| q.poll_events()).unwrap();
+	}
+
+	#[test]
+	fn watcher_sees_latest_value() {
+		let sender = Watch::create(1);
+		let watcher = sender.subscribe();
+		sender.send(2);
+		sender.send(3);
+		assert!(watcher.wait_changed());
+		assert_eq!(watcher.get(), 3);
+		assert_eq!(sender.get(), 3);
+	}
+
+	#[test]
+	fn copy_can_be_sent_back() {
+		let sender = Watch::create(~"a");
+		let watcher = sender.subscribe();
+		let value = watcher.get();
+		sender.send(value + "b");
+		assert_eq!(watcher.get(), ~"ab");
+	}
+
+	#[test]
+	fn closed_after_last_sender() {
+		let sender = Watch::create(0);
+		let other = sender.clone();
+		let watcher = sender.subscribe();
+		drop(sender);
+		assert!(!watcher.is_closed());
+		drop(other);
+		assert!(!watcher.wait_changed());
+		assert!(watcher.is_closed());
+	}
+
+	#[test]
+	fn changes_are_coalesced_into_one_event() {
+		let mut queue = EventQueue::new();
+		let sender = Watch::create(0);
+		let mut watcher = Watcher::from_blocking_watcher(sender.subscribe(), &queue);
+		sender.send(1);
+		sender.send(2);
+		sender.send(3);
+		poll(&queue);
+		assert_eq!(pending(&queue), 1);
+		// The event is still pending, so no second one is queued
+		sender.send(4);
+		poll(&queue);
+		assert_eq!(pending(&queue), 1);
+
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::ValueChangedEvent => {},
+			_ => fail!("Expected a ValueChangedEvent")
+		}
+		assert!(event.originates_from(watcher));
+		assert_eq!(watcher.get(), 4);
+		assert_eq!(pending(&queue), 0);
+	}
+}
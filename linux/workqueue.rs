@@ -0,0 +1,502 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cast;
+use std::libc;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::unstable::mutex::Mutex;
+use std::sync::arc::UnsafeArc;
+use collections::ringbuf::RingBuf;
+use collections::deque::Deque;
+
+use super::events;
+use super::channel::{TrySendError, Closed, SendError};
+use super::eventqueue::EventQueue;
+use super::eventqueue::IEventQueue;
+use super::eventqueueimpl::EventQueueImpl;
+use super::syscalls;
+use super::helpers;
+
+pub struct BlockingReceiver<T> {
+	priv data: UnsafeArc<SharedQueueData<T>>
+}
+
+pub struct Transmitter<T> {
+	priv data: UnsafeArc<SharedQueueData<T>>
+}
+
+struct SharedQueueData<T> {
+	queue: RingBuf<T>,
+	mutex: Mutex,
+	nr_senders: uint,
+	nr_receivers: uint,
+	// eventfds of all evented receivers
+	receiver_fds: ~[i32],
+	// eventfds of evented receivers that wait for a message, longest waiting first
+	idle_receivers: RingBuf<i32>,
+	// Evented receivers that were woken for a message but didn't take it yet
+	notified_receivers: uint,
+	// Blocking receivers that wait in recv
+	blocked_receivers: uint
+}
+
+/**
+ * A multi-producer multi-consumer channel for distributing jobs.
+ * Each message is delivered to exactly one receiver. Receivers can be
+ * cloned and registered at different EventQueues. Idle receivers are woken
+ * in the order in which they became idle and only one per message.
+ */
+pub struct WorkQueue<T>;
+
+impl <T:Send> WorkQueue<T> {
+	pub fn create_blocking() -> (BlockingReceiver<T>, Transmitter<T>) {
+		let shared_data: UnsafeArc<SharedQueueData<T>>
+			= UnsafeArc::new(SharedQueueData {
+				queue: RingBuf::new(),
+				mutex: unsafe { Mutex::new() },
+				nr_senders: 1,
+				nr_receivers: 1,
+				receiver_fds: ~[],
+				idle_receivers: RingBuf::new(),
+				notified_receivers: 0,
+				blocked_receivers: 0
+		});
+		(BlockingReceiver{data: shared_data.clone()}, Transmitter{data: shared_data})
+	}
+
+	pub fn create(event_queue: &EventQueue) -> (~Receiver<T>, Transmitter<T>) {
+		let (rx,tx) = WorkQueue::<T>::create_blocking();
+		(Receiver::from_blocking_receiver(rx, event_queue), tx)
+	}
+}
+
+fn signal_eventfd(fd: i32) {
+	if helpers::signal_eventfd(fd) == -1 {
+		fail!("Error on writing to eventfd: {}", helpers::last_error().desc);
+	}
+}
+
+/**
+ * Wakes one receiver for each message that isn't claimed by a notified receiver.
+ * Idle evented receivers are preferred over blocking ones.
+ * Must be called with the mutex held.
+ */
+unsafe fn dispatch<T>(data: *mut SharedQueueData<T>) {
+	while (*data).queue.len() > (*data).notified_receivers
+	      && (*data).idle_receivers.len() > 0 {
+		let fd = (*data).idle_receivers.pop_front().unwrap();
+		(*data).notified_receivers += 1;
+		signal_eventfd(fd);
+	}
+	if (*data).queue.len() > (*data).notified_receivers
+	   && (*data).blocked_receivers > 0 {
+		(*data).mutex.signal();
+	}
+}
+
+/// Wakes all receivers so that they can detect a closed queue. Must be called with the mutex held.
+unsafe fn notify_closed<T>(data: *mut SharedQueueData<T>) {
+	for fd in (*data).receiver_fds.iter() {
+		signal_eventfd(*fd);
+	}
+	for _ in range(0, (*data).blocked_receivers) {
+		(*data).mutex.signal();
+	}
+}
+
+impl<T:Send> BlockingReceiver<T> {
+	/// Returns None when all senders are gone and the queue is empty
+	pub fn recv(&self) -> Option<T> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			while (*data).queue.len() <= (*data).notified_receivers && (*data).nr_senders != 0 {
+				(*data).blocked_receivers += 1;
+				(*data).mutex.wait();
+				(*data).blocked_receivers -= 1;
+			}
+			let ret = if (*data).queue.len() > (*data).notified_receivers {
+				(*data).queue.pop_front()
+			}
+			else { None };
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+
+	pub fn try_recv(&self) -> Option<T> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let ret = if (*data).queue.len() > (*data).notified_receivers {
+				(*data).queue.pop_front()
+			}
+			else { None };
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+}
+
+impl<T:Send> Clone for BlockingReceiver<T> {
+	fn clone(&self) -> BlockingReceiver<T> {
+		let new = BlockingReceiver{data: self.data.clone()};
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_receivers += 1;
+			(*data).mutex.unlock();
+		}
+		new
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for BlockingReceiver<T> {
+	fn drop(&mut self) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_receivers -= 1;
+			if (*data).nr_receivers == 0 {
+				(*data).queue.clear();
+			}
+			(*data).mutex.unlock();
+		}
+	}
+}
+
+/**
+ * An evented receiver of a work queue.
+ * A ChannelMessageEvent means that one message is reserved for this receiver.
+ * It must be fetched with `recv()` before the receiver is woken for the next one.
+ */
+pub struct Receiver<T> {
+	priv process_func: fn(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32),
+	priv receiver: BlockingReceiver<T>,
+	priv fd: i32,
+	priv message_pending: bool,
+	priv closed: bool,
+	priv event_queue: Rc<RefCell<EventQueueImpl>>,
+	priv event_source_info: Rc<events::EventSourceInfo>
+}
+
+impl<T> events::EventSource for Receiver<T> {
+	fn get_event_source_info<'a>(&'a self) -> &'a Rc<events::EventSourceInfo> {
+		&self.event_source_info
+	}
+}
+
+impl<T:Send> Receiver<T> {
+	pub fn from_blocking_receiver(blocking_receiver: BlockingReceiver<T>, event_queue: &EventQueue) -> ~Receiver<T> {
+		let fd = unsafe { syscalls::eventfd(0, 0) };
+		if fd == -1 {
+			fail!("Creating eventfd for receiver failed: {}", helpers::last_error().desc);
+		}
+
+		let receiver = ~Receiver{
+			receiver: blocking_receiver,
+			fd: fd,
+			message_pending: false,
+			closed: false,
+			event_queue: event_queue._get_impl(),
+			process_func: Receiver::<T>::process_epoll_events,
+			event_source_info: Rc::new(events::EventSourceInfo::new())
+		};
+
+		let callback: *libc::c_void = unsafe { cast::transmute(&receiver.process_func) };
+		receiver.event_queue.borrow().with_mut(|q|
+			q.register_fd(fd, syscalls::EPOLLIN, callback)
+		);
+
+		let data = receiver.receiver.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).receiver_fds.push(fd);
+			(*data).idle_receivers.push_back(fd);
+			dispatch(data);
+			if (*data).nr_senders == 0 {
+				signal_eventfd(fd);
+			}
+			(*data).mutex.unlock();
+		}
+		receiver
+	}
+
+	/**
+	 * Fetches the message that was announced by a ChannelMessageEvent.
+	 * Afterwards the receiver is idle again and queued behind all other idle receivers.
+	 */
+	pub fn recv(&mut self) -> Option<T> {
+		if !self.message_pending { return None; }
+		self.message_pending = false;
+
+		let data = self.receiver.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let ret = (*data).queue.pop_front();
+			(*data).notified_receivers -= 1;
+			(*data).idle_receivers.push_back(self.fd);
+			dispatch(data);
+			if (*data).nr_senders == 0 && (*data).queue.len() == 0 {
+				notify_closed(data);
+			}
+			(*data).mutex.unlock();
+			ret
+		}
+	}
+
+	fn process_epoll_events(func_ptr: *libc::c_void, event_queue: &mut EventQueueImpl, epoll_events: u32) {
+		unsafe {
+			let receiver: *mut Receiver<T> = func_ptr as *mut Receiver<T>;
+			let data = (*receiver).receiver.data.get();
+
+			if epoll_events & syscalls::EPOLLIN != 0 {
+				let buffer = [0, ..8];
+
+				let ret = helpers::retry(||
+					libc::read((*receiver).fd,
+						       buffer.as_ptr() as *mut libc::c_void,
+						       buffer.len() as libc::size_t) as i32
+				);
+
+				if ret == 8 {
+					(*data).mutex.lock();
+					let fd = (*receiver).fd;
+					let idle = (*data).idle_receivers.iter().any(|f| *f == fd);
+					if !idle && !(*receiver).message_pending {
+						(*receiver).message_pending = true;
+						event_queue.push_back_event(events::Event {
+							event_type: events::ChannelMessageEvent,
+							is_valid: true,
+							source_info: (*receiver).event_source_info.clone()
+						});
+					}
+					else if idle && !(*receiver).closed
+					        && (*data).nr_senders == 0 && (*data).queue.len() == 0 {
+						(*receiver).closed = true;
+						event_queue.push_back_event(events::Event {
+							event_type: events::ChannelClosedEvent,
+							is_valid: true,
+							source_info: (*receiver).event_source_info.clone()
+						});
+					}
+					(*data).mutex.unlock();
+				}
+			}
+		}
+	}
+
+	fn remove_pending_events(&mut self) {
+		self.event_queue.borrow().with_mut(|q|
+			q.remove_pending_events(
+				|ev|ev.originates_from(self))
+		);
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for Receiver<T> {
+	fn drop(&mut self) {
+		let data = self.receiver.data.get();
+		let fd = self.fd;
+		unsafe {
+			(*data).mutex.lock();
+			(*data).receiver_fds.retain(|f| *f != fd);
+			let idle_before = (*data).idle_receivers.len();
+			let idle: ~[i32] = (*data).idle_receivers.iter().map(|f| *f).filter(|f| *f != fd).collect();
+			if idle.len() == idle_before {
+				// The receiver was notified. Hand its message over to another one.
+				(*data).notified_receivers -= 1;
+			}
+			(*data).idle_receivers.clear();
+			for f in idle.iter() {
+				(*data).idle_receivers.push_back(*f);
+			}
+			dispatch(data);
+			(*data).mutex.unlock();
+			libc::close(fd);
+		}
+		self.remove_pending_events();
+	}
+}
+
+impl<T:Send> Transmitter<T> {
+	/// Hands the message back if all receivers are gone
+	pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+		match self.try_send(t) {
+			Err(Closed(msg)) => Err(SendError(msg)),
+			_ => Ok(())
+		}
+	}
+
+	pub fn is_closed(&self) -> bool {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			let closed = (*data).nr_receivers == 0;
+			(*data).mutex.unlock();
+			closed
+		}
+	}
+
+	/// Fails with `Closed` when all receivers are gone
+	pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			if (*data).nr_receivers == 0 {
+				(*data).mutex.unlock();
+				return Err(Closed(t));
+			}
+			(*data).queue.push_back(t);
+			dispatch(data);
+			(*data).mutex.unlock();
+		}
+		Ok(())
+	}
+}
+
+#[unsafe_destructor]
+impl<T:Send> Drop for Transmitter<T> {
+	fn drop(&mut self) {
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_senders -= 1;
+			if (*data).nr_senders == 0 {
+				notify_closed(data);
+			}
+			(*data).mutex.unlock();
+		}
+	}
+}
+
+impl<T:Send> Clone for Transmitter<T> {
+	fn clone(&self) -> Transmitter<T> {
+		let new = Transmitter{data: self.data.clone()};
+		let data = self.data.get();
+		unsafe {
+			(*data).mutex.lock();
+			(*data).nr_senders += 1;
+			(*data).mutex.unlock();
+		}
+		new
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use eventqueue::EventQueue;
+	use events;
+	use channel::SendError;
+	use super::{WorkQueue, Receiver};
+
+	#[test]
+	fn each_message_is_delivered_once() {
+		let mut queue = EventQueue::new();
+		let (rx, tx) = WorkQueue::<int>::create_blocking();
+		let mut first = Receiver::from_blocking_receiver(rx.clone(), &queue);
+		let mut second = Receiver::from_blocking_receiver(rx, &queue);
+		for i in range(0, 10) {
+			assert!(tx.send(i).is_ok());
+		}
+		let mut received = ~[];
+		while received.len() < 10 {
+			let event = queue.next_event().unwrap();
+			match event.event_type {
+				events::ChannelMessageEvent => {},
+				_ => fail!("Expected a ChannelMessageEvent")
+			}
+			let msg = if event.originates_from(first) {
+				first.recv()
+			}
+			else {
+				assert!(event.originates_from(second));
+				second.recv()
+			};
+			received.push(msg.unwrap());
+		}
+		received.sort();
+		assert_eq!(received, range(0, 10).collect::<~[int]>());
+		assert!(first.recv().is_none());
+		assert!(second.recv().is_none());
+	}
+
+	#[test]
+	fn longest_idle_receiver_is_woken_alone() {
+		let mut queue = EventQueue::new();
+		let (rx, tx) = WorkQueue::<int>::create_blocking();
+		let mut first = Receiver::from_blocking_receiver(rx.clone(), &queue);
+		let mut second = Receiver::from_blocking_receiver(rx, &queue);
+		assert!(tx.send(1).is_ok());
+		let event = queue.next_event().unwrap();
+		assert!(event.originates_from(first));
+		assert_eq!(first.recv(), Some(1));
+		// The first receiver is idle again, but behind the second one
+		assert!(tx.send(2).is_ok());
+		let event = queue.next_event().unwrap();
+		assert!(event.originates_from(second));
+		assert!(!first.message_pending);
+		let data = first.receiver.data.get();
+		unsafe {
+			assert_eq!((*data).notified_receivers, 1);
+			assert_eq!((*data).idle_receivers.len(), 1);
+		}
+		assert_eq!(second.recv(), Some(2));
+	}
+
+	#[test]
+	fn message_of_dropped_receiver_is_handed_over() {
+		let mut queue = EventQueue::new();
+		let (rx, tx) = WorkQueue::<int>::create_blocking();
+		let first = Receiver::from_blocking_receiver(rx.clone(), &queue);
+		let mut second = Receiver::from_blocking_receiver(rx, &queue);
+		assert!(tx.send(1).is_ok());
+		// The first receiver was notified, but is dropped before it takes the message
+		drop(first);
+		let event = queue.next_event().unwrap();
+		assert!(event.originates_from(second));
+		assert_eq!(second.recv(), Some(1));
+	}
+
+	#[test]
+	fn closed_event_follows_the_last_message() {
+		let mut queue = EventQueue::new();
+		let (mut rx, tx) = WorkQueue::<int>::create(&queue);
+		assert!(tx.send(1).is_ok());
+		drop(tx);
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::ChannelMessageEvent => {},
+			_ => fail!("Expected a ChannelMessageEvent")
+		}
+		assert_eq!(rx.recv(), Some(1));
+		let event = queue.next_event().unwrap();
+		match event.event_type {
+			events::ChannelClosedEvent => {},
+			_ => fail!("Expected a ChannelClosedEvent")
+		}
+		assert!(event.originates_from(rx));
+	}
+
+	#[test]
+	fn send_fails_without_receivers() {
+		let queue = EventQueue::new();
+		let (rx, tx) = WorkQueue::<int>::create(&queue);
+		drop(rx);
+		assert!(tx.is_closed());
+		match tx.send(1) {
+			Err(SendError(1)) => {},
+			_ => fail!("Expected a SendError")
+		}
+	}
+}
@@ -4,4 +4,6 @@ rustc lib.rs
 rustc example.rs -L .
 rustc channelbench.rs -L .
 rustc syncchannelbench.rs -L .
-rustc serverexample.rs -L .
\ No newline at end of file
+rustc serverexample.rs -L .
+rustc --test lib.rs -o revbiotest
+./revbiotest
\ No newline at end of file
@@ -0,0 +1,549 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io;
+use std::io::{File, IoError};
+use std::os;
+
+use super::IoResult;
+
+static SECS_PER_DAY: i64 = 86400;
+
+/// The day of a POSIX TZ rule on which daylight saving time starts or ends
+enum RuleDay {
+	/// Jn: 1 to 365, February 29th is never counted
+	JulianDay(i64),
+	/// n: 0 to 365, February 29th is counted in leap years
+	YearDay(i64),
+	/// Mm.w.d: day d (0 = Sunday) of week w (5 = last) of month m
+	MonthWeekDay(i64, i64, i64)
+}
+
+struct Rule {
+	day: RuleDay,
+	/// Local time of the switch in seconds after midnight
+	time: i64
+}
+
+/// The rule of a POSIX TZ string like "CET-1CEST,M3.5.0,M10.5.0/3"
+struct PosixZone {
+	/// Seconds east of UTC
+	std_offset: i64,
+	/// Offset, start and end of daylight saving time
+	dst: Option<(i64, Rule, Rule)>
+}
+
+/**
+ * The UTC offsets of a time zone.
+ * Times before the last transition of a TZif file (see tzfile(5)) are looked
+ * up in its transition table, later times are calculated from the POSIX TZ
+ * string in the footer of the file.
+ */
+pub struct ZoneInfo {
+	priv transitions: ~[i64],
+	priv transition_types: ~[u8],
+	/// Seconds east of UTC for each local time type
+	priv offsets: ~[i64],
+	priv rule: Option<PosixZone>
+}
+
+fn unknown_zone(name: &str) -> IoError {
+	IoError {
+		kind: io::InvalidInput,
+		desc: "Unknown time zone",
+		detail: Some(name.to_owned())
+	}
+}
+
+impl ZoneInfo {
+	/**
+	 * Loads a zone of the tz database like "Europe/Berlin" from TZDIR or
+	 * /usr/share/zoneinfo. Like the TZ variable the name can also be a
+	 * POSIX TZ string like "CET-1CEST,M3.5.0,M10.5.0/3".
+	 */
+	pub fn load(name: &str) -> IoResult<ZoneInfo> {
+		if name.len() == 0 || name.starts_with("/") || name.contains("..") {
+			return Err(unknown_zone(name));
+		}
+		let dir = os::getenv("TZDIR").unwrap_or(~"/usr/share/zoneinfo");
+		let path = Path::new(dir).join(name);
+		if !path.is_file() {
+			return match parse_posix(name.as_bytes()) {
+				Some(rule) => Ok(ZoneInfo {
+					transitions: ~[],
+					transition_types: ~[],
+					offsets: ~[rule.std_offset],
+					rule: Some(rule)
+				}),
+				None => Err(unknown_zone(name))
+			};
+		}
+		let mut file = if_ok!(File::open(&path));
+		let data = if_ok!(file.read_to_end());
+		match ZoneInfo::parse(data) {
+			Some(zone) => Ok(zone),
+			None => Err(IoError {
+				kind: io::InvalidInput,
+				desc: "Invalid time zone file",
+				detail: Some(name.to_owned())
+			})
+		}
+	}
+
+	/// Parses the contents of a TZif file. Leap seconds are ignored.
+	pub fn parse(data: &[u8]) -> Option<ZoneInfo> {
+		let v1 = match read_header(data, 0) {
+			Some(header) => header,
+			None => return None
+		};
+		// Version 2 files repeat the data with 64 bit times after the version 1 data
+		let (header, pos, time_size) = if data[4] >= '2' as u8 {
+			let v2_pos = 44 + v1.data_len(4);
+			match read_header(data, v2_pos) {
+				Some(header) => (header, v2_pos + 44, 8),
+				None => return None
+			}
+		}
+		else {
+			(v1, 44, 4)
+		};
+		let end = pos + header.data_len(time_size);
+		if data.len() < end || header.typecnt == 0 {
+			return None;
+		}
+
+		let mut transitions = ~[];
+		let mut transition_types = ~[];
+		let mut offsets = ~[];
+		for i in range(0, header.timecnt) {
+			let start = pos + i * time_size;
+			transitions.push(read_be(data.slice(start, start + time_size)));
+		}
+		let types_pos = pos + header.timecnt * time_size;
+		for i in range(0, header.timecnt) {
+			let time_type = data[types_pos + i];
+			if time_type as uint >= header.typecnt {
+				return None;
+			}
+			transition_types.push(time_type);
+		}
+		let offsets_pos = types_pos + header.timecnt;
+		for i in range(0, header.typecnt) {
+			let start = offsets_pos + i * 6;
+			offsets.push(read_be(data.slice(start, start + 4)));
+		}
+
+		let mut rule = None;
+		if time_size == 8 && data.len() > end && data[end] == '\n' as u8 {
+			let footer = data.slice_from(end + 1);
+			match footer.iter().position(|&c| c == '\n' as u8) {
+				Some(len) if len > 0 => {
+					rule = parse_posix(footer.slice_to(len));
+					if rule.is_none() {
+						return None;
+					}
+				},
+				_ => {}
+			}
+		}
+
+		Some(ZoneInfo {
+			transitions: transitions,
+			transition_types: transition_types,
+			offsets: offsets,
+			rule: rule
+		})
+	}
+
+	/// Returns the offset in seconds east of UTC that is in effect at `time`
+	pub fn utc_offset(&self, time: i64) -> i64 {
+		let len = self.transitions.len();
+		match self.rule {
+			Some(ref rule) if len == 0 || time >= self.transitions[len - 1] => {
+				return rule.utc_offset(time);
+			},
+			_ => {}
+		}
+		if len == 0 || time < self.transitions[0] {
+			return self.offsets[0];
+		}
+		// Find the last transition at or before the time
+		let mut low = 0;
+		let mut high = len;
+		while high - low > 1 {
+			let mid = (low + high) / 2;
+			if self.transitions[mid] <= time { low = mid; } else { high = mid; }
+		}
+		self.offsets[self.transition_types[low] as uint]
+	}
+}
+
+/// The counts of a TZif header
+struct Header {
+	isutcnt: uint,
+	isstdcnt: uint,
+	leapcnt: uint,
+	timecnt: uint,
+	typecnt: uint,
+	charcnt: uint
+}
+
+impl Header {
+	/// Returns the length of the data that follows the header
+	fn data_len(&self, time_size: uint) -> uint {
+		self.timecnt * (time_size + 1) + self.typecnt * 6 + self.charcnt
+		+ self.leapcnt * (time_size + 4) + self.isstdcnt + self.isutcnt
+	}
+}
+
+fn read_header(data: &[u8], pos: uint) -> Option<Header> {
+	if data.len() < pos + 44 || data.slice(pos, pos + 4) != bytes!("TZif") {
+		return None;
+	}
+	let mut counts = [0u, ..6];
+	for i in range(0u, 6) {
+		let start = pos + 20 + i * 4;
+		let count = read_be(data.slice(start, start + 4));
+		// Every counted item takes at least one byte
+		if count < 0 || count as uint > data.len() {
+			return None;
+		}
+		counts[i] = count as uint;
+	}
+	Some(Header {
+		isutcnt: counts[0],
+		isstdcnt: counts[1],
+		leapcnt: counts[2],
+		timecnt: counts[3],
+		typecnt: counts[4],
+		charcnt: counts[5]
+	})
+}
+
+/// Reads a signed big endian number
+fn read_be(bytes: &[u8]) -> i64 {
+	let mut value: i64 = if bytes[0] & 0x80 != 0 { -1 } else { 0 };
+	for &b in bytes.iter() {
+		value = (value << 8) | b as i64;
+	}
+	value
+}
+
+struct TzParser<'a> {
+	s: &'a [u8],
+	pos: uint
+}
+
+impl<'a> TzParser<'a> {
+	fn at_end(&self) -> bool {
+		self.pos == self.s.len()
+	}
+
+	fn next_is(&self, c: char) -> bool {
+		!self.at_end() && self.s[self.pos] == c as u8
+	}
+
+	fn eat(&mut self, c: char) -> bool {
+		if self.next_is(c) {
+			self.pos += 1;
+			true
+		}
+		else {
+			false
+		}
+	}
+
+	/// Skips a zone abbreviation like "CET" or "<+03>"
+	fn abbreviation(&mut self) -> bool {
+		let start = self.pos;
+		if self.eat('<') {
+			while !self.at_end() && !self.next_is('>') {
+				self.pos += 1;
+			}
+			return self.pos >= start + 4 && self.eat('>');
+		}
+		while !self.at_end() && (self.s[self.pos] as char).is_alphabetic() {
+			self.pos += 1;
+		}
+		self.pos >= start + 3
+	}
+
+	fn number(&mut self) -> Option<i64> {
+		let start = self.pos;
+		let mut value = 0;
+		while !self.at_end() && (self.s[self.pos] as char).is_digit() && self.pos - start < 3 {
+			value = value * 10 + (self.s[self.pos] - '0' as u8) as i64;
+			self.pos += 1;
+		}
+		if self.pos > start { Some(value) } else { None }
+	}
+
+	/// Parses [+-]hh[:mm[:ss]] into seconds
+	fn time(&mut self) -> Option<i64> {
+		let sign = if self.eat('-') { -1 } else { self.eat('+'); 1 };
+		let mut secs = match self.number() {
+			Some(hours) if hours <= 167 => hours * 3600,
+			_ => return None
+		};
+		if self.eat(':') {
+			match self.number() {
+				Some(minutes) if minutes < 60 => secs += minutes * 60,
+				_ => return None
+			}
+			if self.eat(':') {
+				match self.number() {
+					Some(seconds) if seconds < 60 => secs += seconds,
+					_ => return None
+				}
+			}
+		}
+		Some(sign * secs)
+	}
+
+	/// Parses date[/time]
+	fn rule(&mut self) -> Option<Rule> {
+		let day = if self.eat('J') {
+			match self.number() {
+				Some(day) if day >= 1 && day <= 365 => JulianDay(day),
+				_ => return None
+			}
+		}
+		else if self.eat('M') {
+			let month = self.number();
+			let week = if self.eat('.') { self.number() } else { None };
+			let weekday = if self.eat('.') { self.number() } else { None };
+			match (month, week, weekday) {
+				(Some(m), Some(w), Some(d)) if m >= 1 && m <= 12 && w >= 1 && w <= 5 && d <= 6 => {
+					MonthWeekDay(m, w, d)
+				},
+				_ => return None
+			}
+		}
+		else {
+			match self.number() {
+				Some(day) if day <= 365 => YearDay(day),
+				_ => return None
+			}
+		};
+		let time = if self.eat('/') {
+			match self.time() {
+				Some(time) => time,
+				None => return None
+			}
+		}
+		else {
+			7200
+		};
+		Some(Rule { day: day, time: time })
+	}
+}
+
+/// Parses a POSIX TZ string, see tzset(3)
+fn parse_posix(s: &[u8]) -> Option<PosixZone> {
+	let mut p = TzParser { s: s, pos: 0 };
+	if !p.abbreviation() {
+		return None;
+	}
+	// POSIX offsets count west of UTC
+	let std_offset = match p.time() {
+		Some(offset) => -offset,
+		None => return None
+	};
+	if p.at_end() {
+		return Some(PosixZone { std_offset: std_offset, dst: None });
+	}
+
+	if !p.abbreviation() {
+		return None;
+	}
+	let dst_offset = if !p.at_end() && !p.next_is(',') {
+		match p.time() {
+			Some(offset) => -offset,
+			None => return None
+		}
+	}
+	else {
+		std_offset + 3600
+	};
+	let (start, end) = if p.at_end() {
+		// Without rules glibc falls back to the US rules
+		(Rule { day: MonthWeekDay(3, 2, 0), time: 7200 },
+		 Rule { day: MonthWeekDay(11, 1, 0), time: 7200 })
+	}
+	else {
+		let start = if p.eat(',') { p.rule() } else { None };
+		let end = if p.eat(',') { p.rule() } else { None };
+		match (start, end) {
+			(Some(start), Some(end)) => (start, end),
+			_ => return None
+		}
+	};
+	if !p.at_end() {
+		return None;
+	}
+	Some(PosixZone { std_offset: std_offset, dst: Some((dst_offset, start, end)) })
+}
+
+impl Rule {
+	/// Returns the local time of the switch in the year as seconds since the epoch
+	fn local_time(&self, year: i64) -> i64 {
+		let jan1 = days_from_civil(year, 1, 1);
+		let day = match self.day {
+			JulianDay(day) => jan1 + day - 1 + if day >= 60 && is_leap_year(year) { 1 } else { 0 },
+			YearDay(day) => jan1 + day,
+			MonthWeekDay(month, week, weekday) => {
+				let first = days_from_civil(year, month, 1);
+				let mut day = first + (weekday - weekday_from_days(first) + 7) % 7 + (week - 1) * 7;
+				// Week 5 is the last week, which might be the 4th
+				let (_, day_month, _) = civil_from_days(day);
+				if day_month != month {
+					day -= 7;
+				}
+				day
+			}
+		};
+		day * SECS_PER_DAY + self.time
+	}
+}
+
+impl PosixZone {
+	fn utc_offset(&self, time: i64) -> i64 {
+		match self.dst {
+			None => self.std_offset,
+			Some((dst_offset, ref start, ref end)) => {
+				let (year, _, _) = civil_from_days(div_floor(time + self.std_offset, SECS_PER_DAY));
+				let dst_start = start.local_time(year) - self.std_offset;
+				let dst_end = end.local_time(year) - dst_offset;
+				let in_dst = if dst_start < dst_end {
+					time >= dst_start && time < dst_end
+				}
+				else {
+					// On the southern hemisphere DST spans the turn of the year
+					time >= dst_start || time < dst_end
+				};
+				if in_dst { dst_offset } else { self.std_offset }
+			}
+		}
+	}
+}
+
+pub fn div_floor(a: i64, b: i64) -> i64 {
+	if a >= 0 { a / b } else { (a - b + 1) / b }
+}
+
+fn is_leap_year(year: i64) -> bool {
+	year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// Returns the days since 1970-01-01 for a date of the proleptic Gregorian calendar
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = div_floor(year, 400);
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	era * 146097 + day_of_era - 719468
+}
+
+/// Returns year, month (1-12) and day (1-31) for days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+	let days = days + 719468;
+	let era = div_floor(days, 146097);
+	let day_of_era = days - era * 146097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let shifted_month = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+	let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+	(year, month, day)
+}
+
+/// Returns the day of the week (0 = Sunday) for days since 1970-01-01
+pub fn weekday_from_days(days: i64) -> i64 {
+	// 1970-01-01 was a thursday
+	(days % 7 + 11) % 7
+}
+
+#[cfg(test)]
+mod test {
+	use super::{ZoneInfo, parse_posix, days_from_civil, civil_from_days, weekday_from_days};
+
+	// Switches to UTC at 1000000000 and back to CET on 2014-10-26, then follows the Berlin rule
+	static TZIF: [u8, ..175] = [
+		0x54, 0x5a, 0x69, 0x66, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+		0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+		0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x0e, 0x10,
+		0x00, 0x00, 0x43, 0x45, 0x54, 0x00, 0x54, 0x5a, 0x69, 0x66, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00,
+		0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+		0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00,
+		0x00, 0x0d, 0x00, 0x00, 0x00, 0x00, 0x3b, 0x9a, 0xca, 0x00, 0x00, 0x00, 0x00, 0x00, 0x54, 0x4c,
+		0x47, 0x90, 0x01, 0x00, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04,
+		0x00, 0x00, 0x1c, 0x20, 0x01, 0x08, 0x43, 0x45, 0x54, 0x00, 0x55, 0x54, 0x43, 0x00, 0x43, 0x45,
+		0x53, 0x54, 0x00, 0x0a, 0x43, 0x45, 0x54, 0x2d, 0x31, 0x43, 0x45, 0x53, 0x54, 0x2c, 0x4d, 0x33,
+		0x2e, 0x35, 0x2e, 0x30, 0x2c, 0x4d, 0x31, 0x30, 0x2e, 0x35, 0x2e, 0x30, 0x2f, 0x33, 0x0a
+	];
+
+	#[test]
+	fn civil_dates() {
+		assert_eq!(days_from_civil(1970, 1, 1), 0);
+		assert_eq!(days_from_civil(2000, 3, 1), 11017);
+		assert_eq!(days_from_civil(1969, 12, 31), -1);
+		assert_eq!(civil_from_days(16159), (2014, 3, 30));
+		assert_eq!(civil_from_days(-1), (1969, 12, 31));
+		assert_eq!(weekday_from_days(0), 4);
+		assert_eq!(weekday_from_days(16159), 0);
+	}
+
+	#[test]
+	fn northern_rule() {
+		let zone = parse_posix(bytes!("CET-1CEST,M3.5.0,M10.5.0/3")).unwrap();
+		assert_eq!(zone.utc_offset(1396141199), 3600);
+		assert_eq!(zone.utc_offset(1396141200), 7200);
+		assert_eq!(zone.utc_offset(1414285199), 7200);
+		assert_eq!(zone.utc_offset(1414285200), 3600);
+	}
+
+	#[test]
+	fn southern_rule() {
+		let zone = parse_posix(bytes!("AEST-10AEDT,M10.1.0,M4.1.0/3")).unwrap();
+		assert_eq!(zone.utc_offset(1396713599), 39600);
+		assert_eq!(zone.utc_offset(1396713600), 36000);
+		assert_eq!(zone.utc_offset(1412438399), 36000);
+		assert_eq!(zone.utc_offset(1412438400), 39600);
+	}
+
+	#[test]
+	fn invalid_rules_are_rejected() {
+		assert!(parse_posix(bytes!("UTC0")).is_some());
+		assert!(parse_posix(bytes!("<+03>-3")).is_some());
+		assert!(parse_posix(bytes!("CET")).is_none());
+		assert!(parse_posix(bytes!("CET-1CEST,M13.5.0,M10.5.0")).is_none());
+		assert!(parse_posix(bytes!("CET-1CEST,M3.5.0")).is_none());
+		assert!(parse_posix(bytes!("Nowhere/Nothing")).is_none());
+	}
+
+	#[test]
+	fn tzif_transitions_and_footer() {
+		let zone = ZoneInfo::parse(TZIF.as_slice()).unwrap();
+		assert_eq!(zone.utc_offset(999999999), 3600);
+		assert_eq!(zone.utc_offset(1000000000), 0);
+		assert_eq!(zone.utc_offset(1414285199), 0);
+		assert_eq!(zone.utc_offset(1414285200), 3600);
+		// 2033-05-18 is calculated from the footer
+		assert_eq!(zone.utc_offset(2000000000), 7200);
+	}
+
+	#[test]
+	fn truncated_tzif_is_rejected() {
+		assert!(ZoneInfo::parse(TZIF.slice_to(120)).is_none());
+		assert!(ZoneInfo::parse(TZIF.slice_to(40)).is_none());
+	}
+}